@@ -1,7 +1,11 @@
 use super::KEY_LEN;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::collections::hash_map::RandomState;
 use std::fmt::{Binary, Debug, Error, Formatter};
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct Key(pub [u8; KEY_LEN]);
@@ -21,6 +25,26 @@ impl Key {
 
         Self(hash)
     }
+
+    // A key nobody else is likely to draw: the time, a counter and the random seed std gives
+    // to every RandomState. Not meant for cryptography.
+    pub fn random() -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        let seed = RandomState::new().build_hasher().finish();
+
+        Self::new(format!(
+            "{}:{}:{}:{}",
+            nanos,
+            COUNTER.fetch_add(1, Ordering::Relaxed),
+            seed,
+            std::process::id()
+        ))
+    }
 }
 
 impl Debug for Key {
@@ -38,8 +62,8 @@ pub struct Distance(pub [u8; KEY_LEN]);
 impl Distance {
     pub fn new(k1: &Key, k2: &Key) -> Distance {
         let mut ret = [0; KEY_LEN];
-        for i in 0..KEY_LEN {
-            ret[i] = k1.0[i] ^ k2.0[i];
+        for (i, byte) in ret.iter_mut().enumerate() {
+            *byte = k1.0[i] ^ k2.0[i];
         }
 
         Self(ret)
@@ -1,14 +1,16 @@
 pub mod key;
+pub mod lookup;
 pub mod network;
 pub mod node;
 pub mod protocol;
 pub mod routing;
+pub mod storage;
 pub mod utils;
 
 // 256 bits --> 32 bytes
 const KEY_LEN: usize = 32;
 
-// a list for each bit of the node ID
+// at most a list for each bit of the node ID
 // 32*8 --> 256
 const N_BUCKETS: usize = KEY_LEN * 8;
 
@@ -21,42 +23,119 @@ const BUF_SIZE: usize = 4096 * 2;
 // response timeout 5000ms
 const TIMEOUT: u64 = 5000;
 
+// lifetime of a value cached right after the k closest nodes of a key,
+// halved for every node further down the lookup path (24 hours)
+const CACHE_TTL: u64 = 60 * 60 * 24;
+
+// RPCs in a row a contact can fail before a replacement takes its place
+const MAX_FAILURES: usize = 3;
+
+// the routing table is saved every 10 minutes, see Config::routes_path
+const ROUTES_SAVE_INTERVAL: u64 = 60 * 10;
+
+// saved contacts are pinged K_PARAM at a time on restart, at most 3 times
+// (15 seconds with every contact down), see Protocol::restore_routes
+const RESTORE_BATCHES: usize = 3;
+
+// attempts over the whole bootstrap list before giving up on joining,
+// waiting 1000ms after the first failed one, twice as long after each next one.
+// Every address resolved from the list waits TIMEOUT for its ping: with all of them down the
+// constructor returns after BOOTSTRAP_ATTEMPTS * addresses * TIMEOUT + 1000ms + 2000ms
+// (18 seconds for a single address)
+const BOOTSTRAP_ATTEMPTS: usize = 3;
+const BOOTSTRAP_BACKOFF: u64 = 1000;
+
+// how often (seconds) a node left without contacts tries to join again
+const REBOOTSTRAP_INTERVAL: u64 = 60;
+
+// peers that must have seen us at the same address before it's advertised,
+// see network::ExternalAddr
+const EXTERNAL_ADDR_VOTES: usize = 3;
+
+// a relay drops the session of a client it didn't hear from for a minute,
+// clients renew it every 20 seconds (which also keeps their NAT open), see Config::relay
+const RELAY_SESSION_TTL: u64 = 60;
+const RELAY_KEEPALIVE: u64 = 20;
+
 // number of concurrent lookups in node lookup
 const ALPHA: usize = 3;
 
+// a lookup query slower than this (1000ms) stops counting against ALPHA
+const QUERY_SOFT_TIMEOUT: u64 = 1000;
+
 const VERBOSE: bool = false;
 
 #[cfg(test)]
 mod tests {
-    use super::key::Distance;
+    use super::key::{Distance, Key};
+    use super::lookup::{
+        query_budget, FindNodes, FindValue, LookupEvent, LookupStrategy, Step, Termination,
+    };
+    use super::network::{compact_addr, ExternalAddr, Message, Request, Response, RpcMessage};
     use super::node::Node;
-    use super::protocol::Protocol;
-    use super::routing::NodeAndDistance;
+    use super::protocol::{repair_targets, Config, Protocol};
+    use super::routing::{self, NodeAndDistance, RoutingTable, SavedContact};
+    use super::storage::{
+        EvictionPolicy, MemoryStore, Quota, RecordStore, Rejection, StoreLimits, StoreResult,
+    };
     use super::utils;
+    use super::{
+        ALPHA, BOOTSTRAP_ATTEMPTS, BOOTSTRAP_BACKOFF, CACHE_TTL, EXTERNAL_ADDR_VOTES, KEY_LEN,
+        K_PARAM, MAX_FAILURES, N_BUCKETS, TIMEOUT,
+    };
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    // loopback, the tests don't need a route to the internet
+    fn local(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
 
     #[test]
     fn compare_distance() {
-        let node0 = Node::new(utils::get_local_ip().unwrap(), 1335);
-        let node1 = Node::new(utils::get_local_ip().unwrap(), 1336);
+        let node0 = Node::new(local(1335));
+        let node1 = Node::new(local(1336));
 
         let dist = Distance::new(&node0.id, &node1.id);
-        let nd0 = NodeAndDistance(node0.clone(), dist.clone());
-        let nd1 = NodeAndDistance(node1.clone(), dist.clone());
+        let nd0 = NodeAndDistance(node0.clone(), dist);
+        let nd1 = NodeAndDistance(node1.clone(), dist);
 
         assert_eq!(nd0, nd1);
+
+        // equal pairs hash alike, whatever their node
+        let set: std::collections::HashSet<NodeAndDistance> =
+            vec![nd0.clone(), nd1].into_iter().collect();
+        assert_eq!(1, set.len());
+
+        // the order is reversed: the closer pair is the greater one
+        let far = NodeAndDistance(node0.clone(), Distance([0xff; KEY_LEN]));
+        let near = NodeAndDistance(node0, Distance([0; KEY_LEN]));
+        assert!(near > nd0 && nd0 > far);
+        assert_eq!(Some(std::cmp::Ordering::Greater), near.partial_cmp(&far));
+
+        let mut heap: std::collections::BinaryHeap<NodeAndDistance> =
+            vec![far.clone(), nd0.clone(), near.clone()]
+                .into_iter()
+                .collect();
+        assert_eq!(Some(near), heap.pop());
+        assert_eq!(Some(nd0), heap.pop());
+        assert_eq!(Some(far), heap.pop());
     }
 
     #[test]
     fn main_test() {
-        let node0 = Node::new(utils::get_local_ip().unwrap(), 1337);
-        let node1 = Node::new(utils::get_local_ip().unwrap(), 1338);
-        let node2 = Node::new(utils::get_local_ip().unwrap(), 1339);
+        let node0 = Node::new(local(1337));
+        let node1 = Node::new(local(1338));
+        let node2 = Node::new(local(1339));
 
-        let interface0 = Protocol::new(node0.ip.clone(), node0.port.clone(), None);
-        let interface1 = Protocol::new(node1.ip.clone(), node1.port.clone(), Some(node0.clone()));
-        let interface2 = Protocol::new(node2.ip.clone(), node2.port.clone(), Some(node0.clone()));
+        let interface0 = Protocol::new(node0.addr, None);
+        let interface1 = Protocol::new(node1.addr, Some(node0.clone()));
+        let interface2 = Protocol::new(node2.addr, Some(node0.clone()));
 
-        interface0.put("some_key".to_owned(), "some_value".to_owned());
+        let report = interface0.put("some_key".to_owned(), "some_value".to_owned());
+        assert!(report.quorum_reached);
+        assert!(report.pending.is_empty());
         let get_res_1 = interface1.get("some_key".to_owned());
         let get_res_2 = interface2.get("some_key".to_owned());
 
@@ -64,9 +143,1017 @@ mod tests {
         assert_eq!(get_res_1.unwrap(), get_res_2.unwrap());
     }
 
+    #[test]
+    fn closest_nodes() {
+        // nobody answers the pings: a full bucket keeps the newcomer as a replacement
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let mut routes = RoutingTable::new(Node::new("10.0.0.1:1".parse().unwrap()), None, sender);
+        for port in 0..500 {
+            routes.update(Node::new(SocketAddr::from(([10, 0, 1, 1], port))));
+        }
+
+        let known: Vec<Node> = routes
+            .kbuckets
+            .iter()
+            .flat_map(|b| b.nodes.iter().cloned())
+            .collect();
+
+        for i in 0..200 {
+            let target = match i {
+                0 => routes.node.id.clone(),
+                1 => known[0].id.clone(),
+                _ => Key::new(format!("target {}", i)),
+            };
+
+            let mut expected: Vec<Distance> = known
+                .iter()
+                .map(|n| Distance::new(&n.id, &target))
+                .collect();
+            expected.sort();
+
+            for &count in &[0, 1, 3, K_PARAM, known.len() + 1] {
+                let found: Vec<Distance> = routes
+                    .get_closest_nodes(&target, count)
+                    .into_iter()
+                    .map(|nd| nd.1)
+                    .collect();
+
+                assert_eq!(&expected[..count.min(known.len())], &found[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn bucket_indexes() {
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let routes = RoutingTable::new(Node::new("10.0.0.1:1".parse().unwrap()), None, sender);
+        let own = routes.node.id.clone();
+
+        assert_eq!(0, routes.bucket_for(&own));
+
+        for i in 0..N_BUCKETS {
+            // the key at distance 2^i is the first one of bucket i
+            let mut first = own.clone();
+            first.0[KEY_LEN - 1 - i / 8] ^= 1 << (i % 8);
+            assert_eq!(i, routes.bucket_for(&first));
+
+            let (low, high) = routes.bucket_range(i);
+            assert_eq!(i, routes.bucket_for(&low));
+            assert_eq!(i, routes.bucket_for(&high));
+            assert!(low.0 <= high.0);
+
+            // the range spans the 2^i keys sharing our ID up to bit i (2 keys for bucket 0)
+            let mut free = [0; KEY_LEN];
+            for b in 0..i.max(1) {
+                free[KEY_LEN - 1 - b / 8] |= 1 << (b % 8);
+            }
+            assert_eq!(Distance(free), Distance::new(&low, &high));
+
+            for _ in 0..4 {
+                let key = routes.random_key_in_bucket(i);
+                assert_eq!(i, routes.bucket_for(&key));
+                assert!(low.0 <= key.0 && key.0 <= high.0);
+            }
+        }
+
+        assert_ne!(
+            routes.random_key_in_bucket(N_BUCKETS - 1),
+            routes.random_key_in_bucket(N_BUCKETS - 1)
+        );
+    }
+
+    #[test]
+    fn bucket_splitting() {
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let mut routes = RoutingTable::new(Node::new("10.0.0.1:1".parse().unwrap()), None, sender);
+        assert_eq!(1, routes.kbuckets.len());
+
+        for port in 0..500 {
+            routes.update(Node::new(SocketAddr::from(([10, 0, 1, 1], port))));
+        }
+
+        // only the buckets close to our own ID were split
+        let len = routes.kbuckets.len();
+        assert!(len > 1 && len < 32);
+
+        let first = N_BUCKETS - len;
+        for (j, bucket) in routes.kbuckets.iter().enumerate() {
+            assert!(bucket.nodes.len() <= K_PARAM);
+            for node in &bucket.nodes {
+                match j {
+                    0 => assert!(routes.bucket_for(&node.id) <= first),
+                    _ => assert_eq!(first + j, routes.bucket_for(&node.id)),
+                }
+            }
+        }
+
+        let known: usize = routes.kbuckets.iter().map(|b| b.nodes.len()).sum();
+        assert!(known > 2 * K_PARAM);
+    }
+
+    #[test]
+    fn stale_contacts() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let mut routes = RoutingTable::new(Node::new("10.0.0.1:1".parse().unwrap()), None, sender);
+        for port in 0..500 {
+            routes.update(Node::new(SocketAddr::from(([10, 0, 1, 1], port))));
+        }
+
+        // the furthest bucket is full and has replacements waiting
+        let j = routes.kbuckets.len() - 1;
+        assert_eq!(K_PARAM, routes.kbuckets[j].nodes.len());
+
+        // a full bucket pings its head once, not for every newcomer
+        let full = routes
+            .kbuckets
+            .iter()
+            .filter(|b| !b.replacements.is_empty())
+            .count();
+        assert_eq!(full, receiver.len());
+        let head = routes.kbuckets[j].nodes[0].clone();
+        routes.update(head);
+        routes.update(routes.kbuckets[j].replacements[0].0.clone());
+        assert_eq!(full + 1, receiver.len());
+        let replacement = routes.kbuckets[j].replacements.last().unwrap().0.clone();
+
+        // an answer resets the count
+        let head = routes.kbuckets[j].nodes[0].clone();
+        routes.failed(&head);
+        routes.update(head.clone());
+        assert_eq!(0, routes.kbuckets[j].contacts[&head.id].failures);
+
+        for _ in 1..MAX_FAILURES {
+            routes.failed(&head);
+        }
+        assert!(routes.kbuckets[j].nodes.iter().any(|n| n.id == head.id));
+        assert_eq!(
+            MAX_FAILURES - 1,
+            routes.kbuckets[j].contacts[&head.id].failures
+        );
+
+        routes.failed(&head);
+        assert!(!routes.kbuckets[j].nodes.iter().any(|n| n.id == head.id));
+        assert!(!routes.kbuckets[j].contacts.contains_key(&head.id));
+        assert_eq!(Some(&replacement), routes.kbuckets[j].nodes.last());
+        assert_eq!(K_PARAM, routes.kbuckets[j].nodes.len());
+
+        // without a replacement, a failing contact stays
+        let bucket = routes
+            .kbuckets
+            .iter()
+            .position(|b| b.replacements.is_empty() && !b.nodes.is_empty())
+            .unwrap();
+        let node = routes.kbuckets[bucket].nodes[0].clone();
+        for _ in 0..2 * MAX_FAILURES {
+            routes.failed(&node);
+        }
+        assert!(routes.kbuckets[bucket]
+            .nodes
+            .iter()
+            .any(|n| n.id == node.id));
+    }
+
     #[test]
     fn dump_interface() {
-        let interface = Protocol::new(utils::get_local_ip().unwrap(), 1400, None);
+        let interface = Protocol::new(local(1400), None);
         utils::dump_interface_state(&interface, "dumps/interface.json");
     }
+
+    #[test]
+    fn custom_store() {
+        let store = Arc::new(MemoryStore::new());
+        let config = Config {
+            store: store.clone(),
+            ..Config::default()
+        };
+        let interface = Protocol::with_config(local(1410), None, config);
+
+        assert_eq!(
+            Some(StoreResult::Accepted),
+            interface.store(interface.node.clone(), "k".to_owned(), "v".to_owned())
+        );
+        assert_eq!(Some("v".to_owned()), store.get("k"));
+        assert_eq!(1, store.len());
+    }
+
+    #[test]
+    fn store_quota() {
+        let node_id = Key::new("quota".to_owned());
+        let store = MemoryStore::new();
+        let limits = StoreLimits {
+            max_records: Some(2),
+            max_value_size: Some(8),
+            max_records_per_source: Some(1),
+            eviction: EvictionPolicy::Lru,
+            ..StoreLimits::default()
+        };
+        let quota = Quota::new(node_id, limits);
+        let src = Some("10.0.0.1".parse().unwrap());
+
+        assert_eq!(
+            Err(Rejection::ValueTooLarge),
+            quota.insert(&store, "k0".to_owned(), "too large!".to_owned(), None, None)
+        );
+
+        assert!(quota
+            .insert(&store, "k0".to_owned(), "v0".to_owned(), src, None)
+            .is_ok());
+        assert_eq!(
+            Err(Rejection::SourceQuotaExceeded),
+            quota.insert(&store, "k1".to_owned(), "v1".to_owned(), src, None)
+        );
+
+        // the third record evicts the least recently used one
+        assert!(quota
+            .insert(&store, "k1".to_owned(), "v1".to_owned(), None, None)
+            .is_ok());
+        quota.touch("k0");
+        assert!(quota
+            .insert(&store, "k2".to_owned(), "v2".to_owned(), None, None)
+            .is_ok());
+
+        assert_eq!(2, store.len());
+        assert_eq!(None, store.get("k1"));
+        assert_eq!(Some("v0".to_owned()), store.get("k0"));
+
+        // a cached copy expires, a replica doesn't
+        let ttl = Some(Duration::from_secs(0));
+        assert!(quota
+            .insert(&store, "k0".to_owned(), "v0".to_owned(), None, ttl)
+            .is_ok());
+        assert!(quota
+            .insert(&store, "k3".to_owned(), "v3".to_owned(), None, ttl)
+            .is_ok());
+        assert!(quota.is_expired("k3"));
+        assert_eq!(1, quota.expire(&store));
+        assert_eq!(Some("v0".to_owned()), store.get("k0"));
+        assert_eq!(None, store.get("k3"));
+
+        // keys and values count against max_bytes
+        let store = MemoryStore::new();
+        let limits = StoreLimits {
+            max_bytes: Some(20),
+            eviction: EvictionPolicy::Lru,
+            ..StoreLimits::default()
+        };
+        let quota = Quota::new(Key::new("bytes".to_owned()), limits);
+        assert_eq!(
+            Err(Rejection::ValueTooLarge),
+            quota.insert(&store, "k".to_owned(), "x".repeat(20), None, None)
+        );
+
+        // records written straight to the store count too
+        store.put("pre".to_owned(), "xxxxxxxxxx".to_owned());
+        assert!(quota
+            .insert(&store, "k0".to_owned(), "v0".to_owned(), None, None)
+            .is_ok());
+        assert!(quota
+            .insert(&store, "k1".to_owned(), "12345678".to_owned(), None, None)
+            .is_ok());
+        assert_eq!(None, store.get("pre"));
+        assert_eq!(2, store.len());
+
+        // 4 + 10 + 4 + 10 bytes don't fit in 20, the least recently used goes
+        assert!(quota
+            .insert(&store, "k2".to_owned(), "8bytes!!".to_owned(), None, None)
+            .is_ok());
+        assert_eq!(None, store.get("k0"));
+        assert_eq!(2, store.len());
+    }
+
+    #[test]
+    fn quorum_read_and_repair() {
+        let node0 = Node::new(local(1420));
+        let node1 = Node::new(local(1421));
+
+        let interface0 = Protocol::new(node0.addr, None);
+        let interface1 = Protocol::new(node1.addr, Some(node0.clone()));
+        let interface2 = Protocol::new(local(1422), Some(node0.clone()));
+
+        // simulate a partial write leaving two replicas out of sync
+        interface0.store(node0.clone(), "k".to_owned(), "old".to_owned());
+        interface0.store(node1.clone(), "k".to_owned(), "new".to_owned());
+
+        let report = interface2.value_lookup_quorum("k".to_owned(), 2);
+        assert!(report.quorum_reached);
+        assert!(report.conflict());
+
+        let mut values: Vec<String> = report.values.iter().map(|(v, _)| v.clone()).collect();
+        values.sort();
+        assert_eq!(vec!["new".to_owned(), "old".to_owned()], values);
+
+        // repairing overwrites the stale replica and fills the ones missing the value
+        let repaired = interface2.read_repair("k".to_owned(), "new".to_owned(), &report);
+        assert!(repaired.iter().any(|n| n.id == node0.id));
+
+        let report = interface2.value_lookup_quorum("k".to_owned(), 2);
+        assert!(!report.conflict());
+        assert_eq!(Some("new".to_owned()), report.value());
+
+        // four nodes can't reach a write quorum of five
+        let config = Config {
+            write_quorum: Some(5),
+            ..Config::default()
+        };
+        let interface3 = Protocol::with_config(local(1423), Some(node0.clone()), config);
+        let report = interface3.put("w".to_owned(), "v".to_owned());
+        assert_eq!(4, report.accepted.len());
+        assert!(!report.quorum_reached);
+
+        // a quorum of 0 still needs one replica, a lonely light client has none
+        let config = Config {
+            write_quorum: Some(0),
+            client_only: true,
+            ..Config::default()
+        };
+        let lonely = Protocol::with_config(local(1425), None, config);
+        let report = lonely.put("w".to_owned(), "v".to_owned());
+        assert!(report.accepted.is_empty());
+        assert!(!report.quorum_reached);
+
+        // nothing tells which of two conflicting values is the fresh one, get leaves both alone
+        interface0.store(node0.clone(), "c".to_owned(), "old".to_owned());
+        interface0.store(node1, "c".to_owned(), "new".to_owned());
+        let config = Config {
+            read_quorum: Some(2),
+            ..Config::default()
+        };
+        let interface4 = Protocol::with_config(local(1424), Some(node0.clone()), config);
+        assert!(interface4.get("c".to_owned()).is_some());
+
+        std::thread::sleep(Duration::from_millis(500));
+        assert_eq!(Some("old".to_owned()), interface0.store.get("c"));
+        assert_eq!(Some("new".to_owned()), interface1.store.get("c"));
+
+        // replicas on the k closest, one cached copy on the closest node missing it past them
+        let path: Vec<(Node, bool)> = (0..K_PARAM as u16 + 5)
+            .map(|i| {
+                let node = Node::new(SocketAddr::from(([10, 0, 2, 1], i)));
+                (node, i == 0 || i == K_PARAM as u16)
+            })
+            .collect();
+        let targets = repair_targets(&path);
+
+        assert_eq!(K_PARAM, targets.len());
+        assert!(targets[..K_PARAM - 1].iter().all(|(_, ttl)| ttl.is_none()));
+        let (cached, ttl) = &targets[K_PARAM - 1];
+        assert_eq!(path[K_PARAM + 1].0, *cached);
+        assert_eq!(Some(Duration::from_secs(CACHE_TTL / 2)), *ttl);
+
+        // right after the k closest the copy lasts a whole day
+        let path: Vec<(Node, bool)> = path.into_iter().map(|(n, _)| (n, false)).collect();
+        let targets = repair_targets(&path);
+        assert_eq!(K_PARAM + 1, targets.len());
+        assert_eq!(path[K_PARAM].0, targets[K_PARAM].0);
+        assert_eq!(Some(Duration::from_secs(CACHE_TTL)), targets[K_PARAM].1);
+    }
+
+    #[test]
+    fn persisted_routes() {
+        let path = "dumps/routes.json";
+        std::fs::create_dir_all("dumps").unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let config = || Config {
+            routes_path: Some(path.to_owned()),
+            ..Config::default()
+        };
+
+        let node0 = Node::new(local(1440));
+        let _ = Protocol::new(node0.addr, None);
+        let interface1 = Protocol::with_config(local(1441), Some(node0.clone()), config());
+
+        // saved once the application drops its last handle
+        let id1 = interface1.node.id.clone();
+        drop(interface1.clone());
+        assert!(routing::load_contacts(path).is_err());
+        drop(interface1);
+        let mut saved = routing::load_contacts(path).unwrap();
+        assert!(saved.iter().any(|c| c.node.id == node0.id));
+        assert!(!saved.iter().any(|c| c.node.id == id1));
+
+        // nobody listens there, it must not make it into the table
+        let dead = Node::new(local(1443));
+        saved.push(SavedContact {
+            node: dead.clone(),
+            last_seen: 0,
+        });
+        std::fs::write(path, serde_json::to_string(&saved).unwrap()).unwrap();
+
+        // restarting without a bootstrap
+        let interface2 = Protocol::with_config(local(1442), None, config());
+        let known: Vec<Node> = interface2
+            .routes
+            .lock()
+            .unwrap()
+            .kbuckets
+            .iter()
+            .flat_map(|b| b.nodes.clone())
+            .collect();
+
+        assert!(known.iter().any(|n| n.id == node0.id));
+        assert!(!known.iter().any(|n| n.id == dead.id));
+
+        // restored contacts keep the order they were seen in, the most recent last
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let mut routes = RoutingTable::new(Node::new("10.0.0.1:1".parse().unwrap()), None, sender);
+        let contacts: Vec<SavedContact> = [30, 10, 20]
+            .iter()
+            .map(|&last_seen| SavedContact {
+                node: Node::new(SocketAddr::from(([10, 0, 3, 1], last_seen))),
+                last_seen: last_seen as u64,
+            })
+            .collect();
+        routes.restore(contacts);
+
+        let ports: Vec<u16> = routes.kbuckets[0].nodes[1..]
+            .iter()
+            .map(|n| n.addr.port())
+            .collect();
+        assert_eq!(vec![10, 20, 30], ports);
+    }
+
+    #[test]
+    fn bootstrap_fallback() {
+        let node0 = Node::new(local(1450));
+        let interface0 = Protocol::new(node0.addr, None);
+        assert!(!interface0.join_report.joined());
+
+        // the first seed is down, the second one answers
+        let config = Config {
+            bootstrap: vec!["localhost:1459".to_owned(), node0.get_addr()],
+            ..Config::default()
+        };
+        let interface1 = Protocol::with_config(local(1451), None, config);
+        let report = &interface1.join_report;
+
+        assert!(report.joined());
+        assert_eq!(
+            Some(node0.id.clone()),
+            report.contact.as_ref().map(|n| n.id.clone())
+        );
+        assert_eq!(1, report.failed.len());
+        assert_eq!("localhost:1459", report.failed[0].0);
+        assert!(report.known >= 1);
+
+        // an IPv4 socket can't reach the IPv6 seed, it's not even pinged
+        let config = Config {
+            bootstrap: vec!["localhost:1459".to_owned(), "[::1]:1450".to_owned()],
+            ..Config::default()
+        };
+        let start = std::time::Instant::now();
+        let lonely = Protocol::with_config(local(1452), None, config);
+        assert!(!lonely.join_report.joined());
+        assert_eq!(0, lonely.join_report.known);
+        assert_eq!(
+            Some(&(
+                "[::1]:1450".to_owned(),
+                "no address of the socket's family".to_owned()
+            )),
+            lonely.join_report.failed.last()
+        );
+
+        // 3 attempts at a single address (localhost is only 127.0.0.1 from there), 1s + 2s backoff
+        let bound = Duration::from_millis(
+            BOOTSTRAP_ATTEMPTS as u64 * TIMEOUT + BOOTSTRAP_BACKOFF + 2 * BOOTSTRAP_BACKOFF,
+        );
+        assert!(start.elapsed() < bound + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn bootstrap_by_address() {
+        // an ID that can't be computed from the address
+        let id = Key::new("not derived from the address".to_owned());
+        let config = Config {
+            id: Some(id.clone()),
+            ..Config::default()
+        };
+        let interface0 = Protocol::with_config(local(1460), None, config);
+        assert_eq!(id, interface0.node.id);
+
+        let interface1 = Protocol::new(local(1461), None);
+        let addr = interface0.node.get_addr().parse().unwrap();
+        let node0 = interface1.ping_addr(addr).unwrap();
+        assert_eq!(id, node0.id);
+
+        let closest = interface1.routes.lock().unwrap().get_closest_nodes(&id, 1);
+        assert_eq!(id, closest[0].0.id);
+
+        // and the other way around, the ID came with the request
+        let closest = interface0
+            .routes
+            .lock()
+            .unwrap()
+            .get_closest_nodes(&interface1.node.id, 1);
+        assert_eq!(interface1.node.id, closest[0].0.id);
+
+        // pinging ourselves isn't joining
+        let own = interface1.node.get_addr().parse().unwrap();
+        assert!(interface1.ping_addr(own).is_none());
+    }
+
+    #[test]
+    fn ipv6() {
+        let node0 = Node::new("[::1]:1470".parse().unwrap());
+        let _ = Protocol::new(node0.addr, None);
+        let interface1 = Protocol::new("[::1]:1471".parse().unwrap(), Some(node0.clone()));
+        let config = Config {
+            bootstrap: vec!["[::1]:1470".to_owned()],
+            ..Config::default()
+        };
+        let interface2 = Protocol::with_config("[::1]:1472".parse().unwrap(), None, config);
+        assert!(interface2.join_report.joined());
+
+        let report = interface1.put("v6_key".to_owned(), "v6_value".to_owned());
+        assert!(report.quorum_reached);
+        assert_eq!(
+            Some("v6_value".to_owned()),
+            interface2.get("v6_key".to_owned())
+        );
+
+        // a dual-stack node is reachable over IPv4 and sees IPv4 peers as such
+        let dual = Protocol::new("[::]:1473".parse().unwrap(), None);
+        let v4 = Protocol::new("127.0.0.1:1474".parse().unwrap(), None);
+        let found = v4.ping_addr("127.0.0.1:1473".parse().unwrap()).unwrap();
+        assert_eq!(dual.node.id, found.id);
+
+        let closest = dual
+            .routes
+            .lock()
+            .unwrap()
+            .get_closest_nodes(&v4.node.id, 1);
+        assert_eq!(v4.node.addr, closest[0].0.addr);
+    }
+
+    #[test]
+    fn compact_addresses() {
+        let v4: SocketAddr = "192.168.1.10:8080".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:8080".parse().unwrap();
+
+        assert_eq!(6, compact_addr::encode(&v4).len());
+        assert_eq!(18, compact_addr::encode(&v6).len());
+        assert_eq!(None, compact_addr::decode(&[1, 2, 3]));
+
+        for addr in &[v4, v6] {
+            assert_eq!(
+                Some(*addr),
+                compact_addr::decode(&compact_addr::encode(addr))
+            );
+
+            let node = Node::new(*addr);
+            let json = serde_json::to_string(&node).unwrap();
+            assert!(json.contains(&hex::encode(compact_addr::encode(addr))));
+            assert_eq!(node, serde_json::from_str(&json).unwrap());
+        }
+
+        let msg = RpcMessage {
+            token: Key::new("token".to_owned()),
+            src: v4,
+            src_id: Key::new("src".to_owned()),
+            dst: v6,
+            dst_id: None,
+            observed: Some(v4),
+            src_relay: Some(v6),
+            forwarded_from: None,
+            client_only: false,
+            msg: Message::Request(Request::Ping),
+        };
+        let decoded: RpcMessage =
+            serde_json::from_str(&serde_json::to_string(&msg).unwrap()).unwrap();
+        assert_eq!((v4, v6), (decoded.src, decoded.dst));
+        assert_eq!(Some(v4), decoded.observed);
+        assert_eq!(
+            (Some(v6), None),
+            (decoded.src_relay, decoded.forwarded_from)
+        );
+
+        // the relay of a node is only sent when it has one
+        let mut node = Node::new(v4);
+        assert!(!serde_json::to_string(&node).unwrap().contains("relay"));
+        node.relay = Some(v6);
+        let json = serde_json::to_string(&node).unwrap();
+        assert_eq!(node, serde_json::from_str(&json).unwrap());
+    }
+
+    #[test]
+    fn responder_id_mismatch() {
+        let interface = Protocol::new("127.0.0.1:1560".parse().unwrap(), None);
+
+        // answers every request with an ID other than the one its address hashes to
+        let impostor = std::net::UdpSocket::bind("127.0.0.1:1561").unwrap();
+        let impostor_id = Key::new("impostor".to_owned());
+        let id = impostor_id.clone();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            while let Ok((len, src)) = impostor.recv_from(&mut buf) {
+                let req: RpcMessage = serde_json::from_slice(&buf[..len]).unwrap();
+                let res = RpcMessage {
+                    token: req.token,
+                    src: req.dst,
+                    src_id: id.clone(),
+                    dst: src,
+                    dst_id: Some(req.src_id),
+                    observed: None,
+                    src_relay: None,
+                    forwarded_from: None,
+                    client_only: false,
+                    msg: Message::Response(Response::Ping),
+                };
+                let _ = impostor.send_to(serde_json::to_string(&res).unwrap().as_bytes(), src);
+            }
+        });
+
+        let expected = Node::new("127.0.0.1:1561".parse().unwrap());
+        assert!(!interface.ping(expected.clone()));
+
+        let routes = interface.routes.lock().unwrap();
+        assert_eq!(None, routes.get(&expected.id));
+        assert_eq!(
+            Some(Node::with_id(expected.addr, impostor_id)),
+            routes.get(&Key::new("impostor".to_owned()))
+        );
+    }
+
+    #[test]
+    fn advertised_address() {
+        let advertised: SocketAddr = "127.0.0.1:1480".parse().unwrap();
+        let config = Config {
+            advertised: Some(advertised),
+            ..Config::default()
+        };
+        let bound = Protocol::with_config("0.0.0.0:1480".parse().unwrap(), None, config);
+        assert_eq!(Node::new(advertised), bound.node);
+
+        let interface = Protocol::new("127.0.0.1:1481".parse().unwrap(), Some(bound.node.clone()));
+        assert!(interface.join_report.joined());
+
+        let report = interface.put("adv_key".to_owned(), "adv_value".to_owned());
+        assert!(report.quorum_reached);
+        assert_eq!(
+            Some("adv_value".to_owned()),
+            bound.get("adv_key".to_owned())
+        );
+
+        // the right address isn't enough, a request for another ID is dropped
+        let impostor = Node::with_id(advertised, Key::new("someone else".to_owned()));
+        assert!(!interface.ping(impostor));
+        assert!(interface.ping(bound.node.clone()));
+    }
+
+    #[test]
+    fn unspecified_bind_ids() {
+        let node0 = Protocol::new("0.0.0.0:1540".parse().unwrap(), None);
+        let node1 = Protocol::new("[::]:1541".parse().unwrap(), None);
+
+        // "0.0.0.0:PORT" is the same in every container, the ID can't come from it
+        assert_ne!(Node::new(node0.node.addr).id, node0.node.id);
+        assert_ne!(Node::new(node1.node.addr).id, node1.node.id);
+        assert_ne!(node0.node.id, node1.node.id);
+        assert_ne!(Key::random(), Key::random());
+
+        // so they see each other as peers, not as themselves
+        let found = node0.ping_addr("127.0.0.1:1541".parse().unwrap()).unwrap();
+        assert_eq!(node1.node.id, found.id);
+        let found = node1.ping_addr("127.0.0.1:1540".parse().unwrap()).unwrap();
+        assert_eq!(node0.node.id, found.id);
+    }
+
+    #[test]
+    fn external_address() {
+        let mut external = ExternalAddr::new("0.0.0.0:1".parse().unwrap(), false);
+        let seen: SocketAddr = "203.0.113.7:1".parse().unwrap();
+
+        // a single peer repeating itself isn't enough
+        for _ in 0..EXTERNAL_ADDR_VOTES {
+            assert_eq!(None, external.vote(Key::new("peer".to_owned()), seen));
+        }
+        for i in 1..EXTERNAL_ADDR_VOTES - 1 {
+            assert_eq!(None, external.vote(Key::new(format!("peer{}", i)), seen));
+        }
+        assert_eq!(
+            Some(seen),
+            external.vote(Key::new("last peer".to_owned()), seen)
+        );
+
+        let mut fixed = ExternalAddr::new("0.0.0.0:1".parse().unwrap(), true);
+        for i in 0..EXTERNAL_ADDR_VOTES {
+            assert_eq!(None, fixed.vote(Key::new(format!("peer{}", i)), seen));
+        }
+
+        // a node bound to 0.0.0.0 learns the address its peers reach it at
+        let bound = Protocol::new("0.0.0.0:1490".parse().unwrap(), None);
+        let peers: Vec<Protocol> = (1491..1491 + EXTERNAL_ADDR_VOTES as u16)
+            .map(|port| Protocol::new(SocketAddr::from(([127, 0, 0, 1], port)), None))
+            .collect();
+
+        for peer in &peers {
+            assert_eq!("0.0.0.0:1490".parse(), Ok(bound.advertised().addr));
+            assert!(bound.ping_addr(peer.node.addr).is_some());
+        }
+        let expected: SocketAddr = "127.0.0.1:1490".parse().unwrap();
+        assert_eq!(expected, bound.advertised().addr);
+        assert_eq!(bound.node.id, bound.advertised().id);
+
+        // and hands it out in its answers
+        match peers[0].request(bound.advertised(), Request::FindNode(bound.node.id.clone())) {
+            Some(Response::FindNode(entries)) => {
+                assert_eq!(bound.advertised(), entries[0].0);
+            }
+            res => panic!("unexpected response: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn hole_punching() {
+        let public = Protocol::new("127.0.0.1:1500".parse().unwrap(), None);
+        let config = Config {
+            simulated_nat: true,
+            ..Config::default()
+        };
+        let natted = Protocol::with_config(
+            "127.0.0.1:1501".parse().unwrap(),
+            Some(public.node.clone()),
+            config,
+        );
+        assert!(natted.join_report.joined());
+
+        // the NAT only lets in the nodes it sent something to
+        let interface = Protocol::new("127.0.0.1:1502".parse().unwrap(), None);
+        assert!(!interface.ping(natted.node.clone()));
+
+        assert!(interface.rendezvous(public.node.clone(), natted.node.clone()));
+        assert!(interface.ping(natted.node.clone()));
+
+        // only contacts of the rendezvous node are asked to punch
+        let stranger = Node::new("127.0.0.1:1509".parse().unwrap());
+        assert!(!interface.rendezvous(public.node.clone(), stranger));
+
+        // and only nodes we reached out to can ask for a punch, pinging us first isn't enough:
+        // nothing gets sent for the others
+        let target = std::net::UdpSocket::bind("127.0.0.1:1505").unwrap();
+        target
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let outsider = Protocol::new("127.0.0.1:1504".parse().unwrap(), None);
+        assert!(outsider.ping(public.node.clone()));
+        let res = outsider.request(
+            public.node.clone(),
+            Request::Punch(target.local_addr().unwrap()),
+        );
+        assert!(matches!(res, Some(Response::Punch(false))));
+        assert!(target.recv_from(&mut [0u8; 64]).is_err());
+
+        // a lookup reaches the NATed node through the node that told about it
+        let joining = Protocol::new("127.0.0.1:1503".parse().unwrap(), Some(public.node.clone()));
+        let closest = joining
+            .routes
+            .lock()
+            .unwrap()
+            .get_closest_nodes(&natted.node.id, 1);
+        assert_eq!(natted.node, closest[0].0);
+    }
+
+    #[test]
+    fn relay_mode() {
+        let relay_addr: SocketAddr = "127.0.0.1:1510".parse().unwrap();
+        let config = Config {
+            relay_capacity: 1,
+            ..Config::default()
+        };
+        let relay = Protocol::with_config(relay_addr, None, config);
+
+        // the client's NAT only lets in the relay, it never talks to anyone else first
+        let config = Config {
+            simulated_nat: true,
+            relay: Some(relay_addr),
+            ..Config::default()
+        };
+        let client = Protocol::with_config(
+            "127.0.0.1:1511".parse().unwrap(),
+            Some(relay.node.clone()),
+            config,
+        );
+        assert!(client.join_report.joined());
+        assert_eq!(Some(relay_addr), client.advertised().relay);
+
+        // peers learn the relay along with the client's contact
+        let interface = Protocol::new("127.0.0.1:1512".parse().unwrap(), Some(relay.node.clone()));
+        let closest = interface
+            .routes
+            .lock()
+            .unwrap()
+            .get_closest_nodes(&client.node.id, 1);
+        assert_eq!(client.advertised(), closest[0].0);
+
+        // another address claiming the client's ID can't take over its session
+        let spoofer = std::net::UdpSocket::bind("127.0.0.1:1515").unwrap();
+        spoofer
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let msg = RpcMessage {
+            token: Key::new("spoofed".to_owned()),
+            src: spoofer.local_addr().unwrap(),
+            src_id: client.node.id.clone(),
+            dst: relay_addr,
+            dst_id: None,
+            observed: None,
+            src_relay: None,
+            forwarded_from: None,
+            client_only: false,
+            msg: Message::Request(Request::Relay),
+        };
+        spoofer
+            .send_to(serde_json::to_string(&msg).unwrap().as_bytes(), relay_addr)
+            .unwrap();
+        let mut buf = [0u8; 4096];
+        let len = spoofer.recv(&mut buf).unwrap();
+        let res: RpcMessage = serde_json::from_slice(&buf[..len]).unwrap();
+        assert!(matches!(res.msg, Message::Response(Response::Relay(false))));
+
+        // a node it never talked to only gets through the relay
+        let stranger = Protocol::new("127.0.0.1:1514".parse().unwrap(), None);
+        assert!(!stranger.ping(Node::with_id(client.node.addr, client.node.id.clone())));
+        assert!(stranger.ping(client.advertised()));
+
+        let report = interface.put("relayed_key".to_owned(), "relayed_value".to_owned());
+        assert!(report.quorum_reached);
+        assert_eq!(
+            Some("relayed_value".to_owned()),
+            client.get("relayed_key".to_owned())
+        );
+
+        // the relay is full
+        let config = Config {
+            relay: Some(relay_addr),
+            ..Config::default()
+        };
+        let refused = Protocol::with_config("127.0.0.1:1513".parse().unwrap(), None, config);
+        assert_eq!(None, refused.advertised().relay);
+    }
+
+    #[test]
+    fn client_only() {
+        let node0 = Protocol::new("127.0.0.1:1520".parse().unwrap(), None);
+        let node1 = Protocol::new("127.0.0.1:1521".parse().unwrap(), Some(node0.node.clone()));
+        let config = Config {
+            client_only: true,
+            ..Config::default()
+        };
+        let client = Protocol::with_config(
+            "127.0.0.1:1522".parse().unwrap(),
+            Some(node0.node.clone()),
+            config,
+        );
+        assert!(client.join_report.joined());
+        assert_eq!(2, client.join_report.known);
+
+        let report = client.put("client_key".to_owned(), "client_value".to_owned());
+        assert!(report.quorum_reached);
+        assert_eq!(
+            Some("client_value".to_owned()),
+            client.get("client_key".to_owned())
+        );
+
+        // the values went to the peers only, and none of them knows the client
+        assert_eq!(None, client.store.get("client_key"));
+        let closest = client
+            .routes
+            .lock()
+            .unwrap()
+            .get_closest_nodes(&client.node.id, K_PARAM);
+        assert!(closest.iter().all(|e| e.0.id != client.node.id));
+        for peer in &[&node0, &node1] {
+            assert_eq!(
+                Some("client_value".to_owned()),
+                peer.store.get("client_key")
+            );
+            assert!(peer
+                .routes
+                .lock()
+                .unwrap()
+                .contacts()
+                .iter()
+                .all(|c| c.node.id != client.node.id));
+        }
+
+        // and it doesn't answer those who would contact it anyway
+        assert!(!node0.ping(client.advertised()));
+    }
+
+    #[test]
+    fn malformed_datagrams() {
+        let interface = Protocol::new("127.0.0.1:1530".parse().unwrap(), None);
+        let peer = Protocol::new("127.0.0.1:1531".parse().unwrap(), None);
+
+        // a valid message whose source address is bad hex, then hex of the wrong length
+        let msg = RpcMessage {
+            token: Key::new("token".to_owned()),
+            src: peer.node.addr,
+            src_id: peer.node.id.clone(),
+            dst: interface.node.addr,
+            dst_id: None,
+            observed: None,
+            src_relay: None,
+            forwarded_from: None,
+            client_only: false,
+            msg: Message::Request(Request::Ping),
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+        let src = hex::encode(compact_addr::encode(&peer.node.addr));
+        assert!(json.contains(&src));
+
+        let garbage = vec![
+            vec![0xff, 0xfe, 0x00],
+            b"not json".to_vec(),
+            json.replacen(&src, "zzzzzzzzzzzz", 1).into_bytes(),
+            json.replacen(&src, "0102", 1).into_bytes(),
+        ];
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        for datagram in &garbage {
+            socket.send_to(datagram, interface.node.addr).unwrap();
+        }
+
+        assert!(peer.ping(interface.node.clone()));
+    }
+
+    // stops at the first node that answers
+    struct FirstAnswer(Key, usize);
+
+    impl LookupStrategy for FirstAnswer {
+        fn request(&self) -> Request {
+            Request::FindNode(self.0.clone())
+        }
+
+        fn handle(&mut self, _from: &NodeAndDistance, _res: Response) -> Step {
+            self.1 += 1;
+            Step::Stop
+        }
+    }
+
+    #[test]
+    fn lookup_widening() {
+        assert_eq!(ALPHA, query_budget(0, 0));
+        assert_eq!(0, query_budget(ALPHA, 0));
+
+        // an answer bringing nothing closer only frees its slot
+        assert_eq!(1, query_budget(ALPHA - 1, 1));
+        assert_eq!(ALPHA - 1, query_budget(1, ALPHA - 1));
+
+        // a full round of them widens the lookup to the k closest
+        assert_eq!(K_PARAM, query_budget(ALPHA - 1, ALPHA));
+        assert_eq!(K_PARAM, query_budget(0, ALPHA + 1));
+    }
+
+    #[test]
+    fn custom_lookup() {
+        let node0 = Node::new(local(1430));
+        let _ = Protocol::new(node0.addr, None);
+        let interface = Protocol::new(local(1431), Some(node0.clone()));
+
+        let mut strategy = FirstAnswer(Key::new("target".to_owned()), 0);
+        let (found, trace) = interface.traced_lookup(&strategy.0.clone(), &mut strategy);
+
+        assert_eq!(1, strategy.1);
+        assert_eq!(1, found.len());
+
+        match trace.events.last() {
+            Some((_, LookupEvent::Terminated { reason, .. })) => {
+                assert_eq!(Termination::Stopped, *reason)
+            }
+            other => panic!("unexpected last event: {:?}", other),
+        }
+        assert!(trace
+            .events
+            .iter()
+            .any(|(_, e)| matches!(e, LookupEvent::QuerySent(_))));
+
+        std::fs::create_dir_all("dumps").unwrap();
+        utils::dump_lookup_trace(&trace, &found, "dumps/lookup_trace.json");
+
+        // a value short of the quorum keeps the lookup going without widening it
+        let from = NodeAndDistance(node0.clone(), Distance::new(&node0.id, &node0.id));
+        let mut find_value = FindValue::new("key".to_owned(), 2);
+        let value = Response::FindValue(routing::FindValueResult::Value("value".to_owned()));
+        assert!(matches!(find_value.handle(&from, value), Step::Found));
+        let value = Response::FindValue(routing::FindValueResult::Value("value".to_owned()));
+        assert!(matches!(find_value.handle(&from, value), Step::Stop));
+
+        // a lookup nobody answers is told apart from one that converged
+        let isolated = Protocol::new(local(1432), None);
+        isolated
+            .routes
+            .lock()
+            .unwrap()
+            .update(Node::new(local(1439)));
+        let (_, trace) = isolated.traced_lookup(
+            &Key::new("target".to_owned()),
+            &mut FindNodes::new(Key::new("target".to_owned())),
+        );
+        match trace.events.last() {
+            Some((_, LookupEvent::Terminated { reason, .. })) => {
+                assert_eq!(Termination::Exhausted, *reason)
+            }
+            other => panic!("unexpected last event: {:?}", other),
+        }
+    }
 }
@@ -0,0 +1,432 @@
+use super::key::Key;
+use super::network::{Request, Response};
+use super::node::Node;
+use super::protocol::{Protocol, ReadReport};
+use super::routing::{FindValueResult, NodeAndDistance};
+use super::{ALPHA, K_PARAM, QUERY_SOFT_TIMEOUT};
+
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+// what a strategy makes of an answer
+#[derive(Debug)]
+pub enum Step {
+    // keep going, with the contacts the answer brought
+    Continue(Vec<NodeAndDistance>),
+    // the answer was what we looked for, but more of them are needed: keep going
+    // without widening the lookup, as an answer bringing nothing closer would
+    Found,
+    // the lookup reached its goal and can end early
+    Stop,
+}
+
+// Everything that changes between lookups: which RPC is sent to the nodes
+// and what is done with their answers. The iteration itself is shared (see run).
+pub trait LookupStrategy {
+    // request sent to every node queried
+    fn request(&self) -> Request;
+
+    // called with every answer, in the order they arrive
+    fn handle(&mut self, from: &NodeAndDistance, res: Response) -> Step;
+}
+
+// FindNode lookup, used to find the k closest nodes to an ID
+#[derive(Debug)]
+pub struct FindNodes {
+    pub id: Key,
+}
+
+impl FindNodes {
+    pub fn new(id: Key) -> Self {
+        Self { id }
+    }
+}
+
+impl LookupStrategy for FindNodes {
+    fn request(&self) -> Request {
+        Request::FindNode(self.id.clone())
+    }
+
+    fn handle(&mut self, _from: &NodeAndDistance, res: Response) -> Step {
+        match res {
+            Response::FindNode(entries) => Step::Continue(entries),
+            _ => Step::Continue(Vec::new()),
+        }
+    }
+}
+
+// FindValue lookup, ends once `quorum` replicas returned the value
+#[derive(Debug)]
+pub struct FindValue {
+    pub k: String,
+    pub quorum: usize,
+    pub report: ReadReport,
+    replicas: usize,
+}
+
+impl FindValue {
+    pub fn new(k: String, quorum: usize) -> Self {
+        Self {
+            k,
+            quorum,
+            report: ReadReport::default(),
+            replicas: 0,
+        }
+    }
+}
+
+impl LookupStrategy for FindValue {
+    fn request(&self) -> Request {
+        Request::FindValue(self.k.clone())
+    }
+
+    fn handle(&mut self, from: &NodeAndDistance, res: Response) -> Step {
+        match res {
+            Response::FindValue(FindValueResult::Nodes(entries)) => {
+                // we didn't get the value we looked for
+                self.report.missing.push(from.clone());
+                self.report.missing.sort_by_key(|a| a.1);
+                Step::Continue(entries)
+            }
+            Response::FindValue(FindValueResult::Value(val)) => {
+                self.replicas += 1;
+                match self.report.values.iter_mut().find(|(v, _)| *v == val) {
+                    Some((_, holders)) => holders.push(from.0.clone()),
+                    None => self.report.values.push((val, vec![from.0.clone()])),
+                }
+
+                self.report.quorum_reached = self.replicas >= self.quorum;
+                if self.report.quorum_reached {
+                    Step::Stop
+                } else {
+                    Step::Found
+                }
+            }
+            _ => Step::Continue(Vec::new()),
+        }
+    }
+}
+
+// why a lookup ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Termination {
+    // the k closest nodes that didn't fail have all responded
+    Converged,
+    // the strategy stopped it early
+    Stopped,
+    // no node responded, e.g. every contact is unreachable
+    Exhausted,
+}
+
+// progress of a lookup, see Protocol::observed_lookup
+#[derive(Debug, Clone)]
+pub enum LookupEvent {
+    QuerySent(NodeAndDistance),
+    ResponseReceived {
+        node: Node,
+        elapsed: Duration,
+        // contacts the answer brought that we didn't know of
+        new_contacts: usize,
+    },
+    // the query stopped counting against ALPHA, its answer may still come
+    SoftTimeout(Node),
+    // the node didn't answer before the RPC timeout
+    Failed {
+        node: Node,
+        elapsed: Duration,
+    },
+    Terminated {
+        reason: Termination,
+        queried: usize,
+    },
+}
+
+// every event of a lookup with the time it happened at (since the lookup started),
+// see Protocol::traced_lookup and utils::dump_lookup_trace
+#[derive(Debug, Clone)]
+pub struct LookupTrace {
+    pub target: Key,
+    pub events: Vec<(Duration, LookupEvent)>,
+    started: Instant,
+}
+
+impl LookupTrace {
+    pub fn new(target: Key) -> Self {
+        Self {
+            target,
+            events: Vec::new(),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn record(&mut self, event: &LookupEvent) {
+        self.events.push((self.started.elapsed(), event.clone()));
+    }
+}
+
+// state of a shortlist entry during a lookup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Contacted {
+    No,
+    Pending,
+    Responded,
+    Failed,
+}
+
+#[derive(Debug)]
+struct Candidate {
+    entry: NodeAndDistance,
+    // the node that told us about this one, asked for a rendezvous if it doesn't answer
+    via: Option<Node>,
+    state: Contacted,
+    sent: Option<Instant>,
+    // took longer than QUERY_SOFT_TIMEOUT, no longer counts against ALPHA
+    slow: bool,
+}
+
+impl Candidate {
+    fn new(entry: NodeAndDistance, via: Option<Node>) -> Self {
+        Self {
+            entry,
+            via,
+            state: Contacted::No,
+            sent: None,
+            slow: false,
+        }
+    }
+}
+
+// how many new queries can be sent, given the ones in flight and the answers received
+// since the closest node seen last improved: once a full round of ALPHA of them brought
+// nothing closer, every one of the k closest not contacted yet is queried
+pub(crate) fn query_budget(in_flight: usize, stale_answers: usize) -> usize {
+    if stale_answers >= ALPHA {
+        K_PARAM
+    } else {
+        ALPHA.saturating_sub(in_flight)
+    }
+}
+
+// Drives an iterative lookup towards `target` keeping a shortlist of the closest nodes heard of.
+// ALPHA queries are kept in flight at all times: a new one is sent as soon as an answer arrives,
+// and a query slower than QUERY_SOFT_TIMEOUT stops counting against ALPHA (its answer is still
+// used if it comes before the RPC timeout). When a full round of ALPHA answers (or failures)
+// doesn't bring a node closer than the closest already seen, every one of the k closest not
+// contacted yet is queried (see query_budget).
+// The lookup ends once the k closest nodes that didn't fail have all responded,
+// or earlier if the strategy says so. Returns the k closest nodes that responded.
+pub fn run<S: LookupStrategy>(
+    protocol: &Protocol,
+    target: &Key,
+    strategy: &mut S,
+) -> Vec<NodeAndDistance> {
+    run_observed(protocol, target, strategy, &mut |_| {})
+}
+
+// same as run, but `observer` is told about every step of the lookup
+pub fn run_observed<S: LookupStrategy>(
+    protocol: &Protocol,
+    target: &Key,
+    strategy: &mut S,
+    observer: &mut dyn FnMut(&LookupEvent),
+) -> Vec<NodeAndDistance> {
+    let soft_timeout = Duration::from_millis(QUERY_SOFT_TIMEOUT);
+    let (sender, receiver) = mpsc::channel::<(Node, Option<Response>)>();
+
+    let routes = protocol
+        .routes
+        .lock()
+        .expect("[FAILED] Lookup::run --> Failed to acquire mutex on Routes");
+
+    let mut shortlist: Vec<Candidate> = routes
+        .get_closest_nodes(target, K_PARAM)
+        .into_iter()
+        .map(|entry| Candidate::new(entry, None))
+        .collect();
+    drop(routes);
+
+    let mut closest_seen = shortlist.iter().map(|c| c.entry.1).min();
+    // answers since the closest node seen last improved
+    let mut stale_answers = 0;
+    let mut termination = Termination::Converged;
+
+    loop {
+        shortlist.sort_by_key(|c| c.entry.1);
+
+        // the k closest that didn't fail
+        let closest: Vec<usize> = (0..shortlist.len())
+            .filter(|&i| shortlist[i].state != Contacted::Failed)
+            .take(K_PARAM)
+            .collect();
+
+        if closest
+            .iter()
+            .all(|&i| shortlist[i].state == Contacted::Responded)
+        {
+            termination = Termination::Converged;
+            break;
+        }
+
+        let in_flight = shortlist
+            .iter()
+            .filter(|c| c.state == Contacted::Pending && !c.slow)
+            .count();
+        let budget = query_budget(in_flight, stale_answers);
+
+        let queries: Vec<usize> = closest
+            .into_iter()
+            .filter(|&i| shortlist[i].state == Contacted::No)
+            .take(budget)
+            .collect();
+
+        for i in queries {
+            let candidate = &mut shortlist[i];
+            candidate.state = Contacted::Pending;
+            candidate.sent = Some(Instant::now());
+
+            observer(&LookupEvent::QuerySent(candidate.entry.clone()));
+
+            let node = candidate.entry.0.clone();
+            let via = candidate.via.clone();
+            let req = strategy.request();
+            let protocol = protocol.worker();
+            let sender = sender.clone();
+
+            std::thread::spawn(move || {
+                let res = protocol.request_via(node.clone(), req, via);
+                // the receiver is gone if the lookup ended in the meantime
+                let _ = sender.send((node, res));
+            });
+        }
+
+        // waiting for the next answer or the next soft timeout
+        let deadline = shortlist
+            .iter()
+            .filter(|c| c.state == Contacted::Pending && !c.slow)
+            .filter_map(|c| c.sent)
+            .min()
+            .map(|sent| sent + soft_timeout);
+
+        let received = match deadline {
+            Some(deadline) => {
+                receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            }
+            // only slow queries left, the RPC timeout bounds the wait
+            None => receiver
+                .recv()
+                .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+
+        match received {
+            Ok((node, res)) => {
+                let i = match shortlist.iter().position(|c| c.entry.0.id == node.id) {
+                    Some(i) => i,
+                    None => continue,
+                };
+
+                let elapsed = shortlist[i]
+                    .sent
+                    .map(|sent| sent.elapsed())
+                    .unwrap_or_default();
+
+                let res = match res {
+                    Some(res) => res,
+                    None => {
+                        stale_answers += 1;
+                        shortlist[i].state = Contacted::Failed;
+                        observer(&LookupEvent::Failed { node, elapsed });
+                        continue;
+                    }
+                };
+                shortlist[i].state = Contacted::Responded;
+
+                let entries = match strategy.handle(&shortlist[i].entry, res) {
+                    Step::Continue(entries) => entries,
+                    Step::Found => {
+                        observer(&LookupEvent::ResponseReceived {
+                            node,
+                            elapsed,
+                            new_contacts: 0,
+                        });
+                        continue;
+                    }
+                    Step::Stop => {
+                        observer(&LookupEvent::ResponseReceived {
+                            node,
+                            elapsed,
+                            new_contacts: 0,
+                        });
+                        termination = Termination::Stopped;
+                        break;
+                    }
+                };
+
+                let mut improved = false;
+                let mut new_contacts = 0;
+                for entry in entries {
+                    if shortlist.iter().any(|c| c.entry == entry) {
+                        continue;
+                    }
+
+                    if closest_seen.is_none_or(|closest| entry.1 < closest) {
+                        closest_seen = Some(entry.1);
+                        improved = true;
+                    }
+                    shortlist.push(Candidate::new(entry, Some(node.clone())));
+                    new_contacts += 1;
+                }
+
+                if improved {
+                    stale_answers = 0;
+                } else {
+                    stale_answers += 1;
+                }
+
+                observer(&LookupEvent::ResponseReceived {
+                    node,
+                    elapsed,
+                    new_contacts,
+                });
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                let now = Instant::now();
+                for c in shortlist.iter_mut() {
+                    if c.state == Contacted::Pending
+                        && !c.slow
+                        && c.sent.is_some_and(|sent| sent + soft_timeout <= now)
+                    {
+                        c.slow = true;
+                        observer(&LookupEvent::SoftTimeout(c.entry.0.clone()));
+                    }
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    // we answer our own queries, only the others tell whether the network was reached
+    let reached = shortlist
+        .iter()
+        .any(|c| c.state == Contacted::Responded && c.entry.0.id != protocol.node.id);
+    if termination == Termination::Converged && !reached {
+        termination = Termination::Exhausted;
+    }
+
+    observer(&LookupEvent::Terminated {
+        reason: termination,
+        queried: shortlist
+            .iter()
+            .filter(|c| c.state != Contacted::No)
+            .count(),
+    });
+
+    shortlist.sort_by_key(|c| c.entry.1);
+    let mut ret: Vec<NodeAndDistance> = shortlist
+        .into_iter()
+        .filter(|c| c.state == Contacted::Responded)
+        .map(|c| c.entry)
+        .collect();
+    ret.truncate(K_PARAM);
+
+    ret
+}
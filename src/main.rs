@@ -1,23 +1,26 @@
 extern crate kademlia_dht;
 use kademlia_dht::node::Node;
 use kademlia_dht::protocol::Protocol;
-use kademlia_dht::utils;
+use std::net::SocketAddr;
 
+#[allow(dead_code)]
 const BIG_TEST: bool = true;
 
 // be careful with the net size, for example my computer can't spawn too many threads
 // messages may also exceed the buffer size used for streaming (see issue #1)
+#[allow(dead_code)]
 const NET_SIZE: usize = 10;
 
 fn main() {
     // searching for nodes close to a key
-    let node0 = Node::new(utils::get_local_ip().unwrap(), 1337);
-    let node1 = Node::new(utils::get_local_ip().unwrap(), 1338);
-    let node2 = Node::new(utils::get_local_ip().unwrap(), 1339);
-
-    let interface0 = Protocol::new(node0.ip.clone(), node0.port.clone(), None);
-    let _ = Protocol::new(node1.ip.clone(), node1.port.clone(), Some(node0.clone()));
-    let interface2 = Protocol::new(node2.ip.clone(), node2.port.clone(), Some(node0.clone()));
+    // loopback addresses, the example runs without a network
+    let node0 = Node::new(SocketAddr::from(([127, 0, 0, 1], 1337)));
+    let node1 = Node::new(SocketAddr::from(([127, 0, 0, 1], 1338)));
+    let node2 = Node::new(SocketAddr::from(([127, 0, 0, 1], 1339)));
+
+    let interface0 = Protocol::new(node0.addr, None);
+    let _ = Protocol::new(node1.addr, Some(node0.clone()));
+    let interface2 = Protocol::new(node2.addr, Some(node0.clone()));
 
     let key = "key-1";
     let value = "value-1";
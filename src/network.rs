@@ -4,29 +4,46 @@ use super::key::Key;
 use super::node::*;
 use super::routing::FindValueResult;
 use super::routing::NodeAndDistance;
+use super::storage::StoreResult;
 use super::BUF_SIZE;
 use super::TIMEOUT;
+use super::{EXTERNAL_ADDR_VOTES, K_PARAM, RELAY_SESSION_TTL};
 
-use std::collections::HashMap;
-use std::net::UdpSocket;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
 use std::str;
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Request {
     Ping,
-    Store(String, String),
+    // key, value and, for cached copies, a time to live in seconds
+    Store(String, String, Option<u64>),
     FindNode(Key),
     FindValue(String),
+    // asks one of our contacts to punch a hole towards the requester, see Protocol::rendezvous
+    Rendezvous(Node),
+    // asks to send a Message::Punch to the given address
+    Punch(#[serde(with = "compact_addr")] SocketAddr),
+    // opens or renews a session with a relay, see Config::relay
+    Relay,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Response {
     Ping,
+    Store(StoreResult),
     FindNode(Vec<NodeAndDistance>),
     FindValue(FindValueResult),
+    // whether the contact punched the hole
+    Rendezvous(bool),
+    // whether the punch was sent, only contacts can ask for one
+    Punch(bool),
+    // whether the relay accepted the session
+    Relay(bool),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -34,60 +51,294 @@ pub enum Message {
     Abort,
     Request(Request),
     Response(Response),
+    // opens the sender's NAT towards the recipient, ignored on arrival
+    Punch,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RpcMessage {
     pub token: Key,
-    pub src: String,
-    pub dst: String,
+    #[serde(with = "compact_addr")]
+    pub src: SocketAddr,
+    // ID of the node sending the message
+    pub src_id: Key,
+    #[serde(with = "compact_addr")]
+    pub dst: SocketAddr,
+    // ID of the node the message is for, None when only its address is known
+    pub dst_id: Option<Key>,
+    // in responses, the address the request came from as seen by the responder
+    #[serde(default, with = "compact_addr::option")]
+    pub observed: Option<SocketAddr>,
+    // relay the sender is reachable through, if any
+    #[serde(default, with = "compact_addr::option")]
+    pub src_relay: Option<SocketAddr>,
+    // set by a relay passing the message on to its client: the address it came from
+    #[serde(default, with = "compact_addr::option")]
+    pub forwarded_from: Option<SocketAddr>,
+    // the sender is a light client, kept out of routing tables (see Config::client_only)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub client_only: bool,
     pub msg: Message,
 }
 
+// a response along with the ID of the node that sent it, None if it didn't answer in time
+pub type Reply = Option<(Key, Response)>;
+
 #[derive(Debug)]
 pub struct ReqWrapper {
     pub token: Key,
-    pub src: String,
+    pub src: SocketAddr,
+    pub src_id: Key,
+    pub src_relay: Option<SocketAddr>,
+    // the request came through our relay
+    pub relayed: bool,
+    pub client_only: bool,
     pub payload: Request,
 }
 
+// The address peers reach us at, voted on from the addresses they observed (see
+// RpcMessage::observed): it changes once EXTERNAL_ADDR_VOTES peers agree on another one
+// and more of them saw it than the current one. Only the latest K_PARAM peers count.
+#[derive(Debug)]
+pub struct ExternalAddr {
+    pub addr: SocketAddr,
+    // observations are ignored, e.g. the address was set in Config::advertised
+    pub fixed: bool,
+    // relay we're reached through, see Config::relay
+    pub relay: Option<SocketAddr>,
+    // latest observation of each peer, oldest first
+    votes: VecDeque<(Key, SocketAddr)>,
+}
+
+impl ExternalAddr {
+    pub fn new(addr: SocketAddr, fixed: bool) -> Self {
+        Self {
+            addr,
+            fixed,
+            relay: None,
+            votes: VecDeque::new(),
+        }
+    }
+
+    // records the address `peer` saw us at, returns the new address if it changed
+    pub fn vote(&mut self, peer: Key, observed: SocketAddr) -> Option<SocketAddr> {
+        if self.fixed {
+            return None;
+        }
+
+        self.votes.retain(|(p, _)| *p != peer);
+        self.votes.push_back((peer, observed));
+        if self.votes.len() > K_PARAM {
+            self.votes.pop_front();
+        }
+
+        let mut tally: HashMap<SocketAddr, usize> = HashMap::new();
+        for (_, addr) in &self.votes {
+            *tally.entry(*addr).or_insert(0) += 1;
+        }
+
+        let current = tally.get(&self.addr).copied().unwrap_or(0);
+        let (best, count) = tally.into_iter().max_by_key(|(_, count)| *count)?;
+        if best == self.addr || count < EXTERNAL_ADDR_VOTES || count <= current {
+            return None;
+        }
+
+        self.addr = best;
+        Some(best)
+    }
+}
+
+// Simulates a NAT in front of the node (port restricted): packets only get in from the
+// addresses the node sent something to. Used to test hole punching, see Config::simulated_nat
+#[derive(Debug, Default)]
+pub struct SimulatedNat {
+    opened: HashSet<SocketAddr>,
+}
+
+impl SimulatedNat {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // we sent something to `addr`, its packets are now let in
+    pub fn open(&mut self, addr: SocketAddr) {
+        self.opened.insert(addr);
+    }
+
+    pub fn accepts(&self, addr: &SocketAddr) -> bool {
+        self.opened.contains(addr)
+    }
+}
+
+// Clients a node relays for (see Config::relay_capacity): the address their packets come
+// from and when they last renewed their session. Sessions last RELAY_SESSION_TTL seconds.
+#[derive(Debug)]
+pub struct RelaySessions {
+    pub capacity: usize,
+    clients: HashMap<Key, (SocketAddr, Instant)>,
+}
+
+impl RelaySessions {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            clients: HashMap::new(),
+        }
+    }
+
+    // Opens or renews the session of `id`, false if there's no room left for it. IDs are
+    // only claimed: until it expires, a session is only renewed from the address that opened
+    // it, or anyone could take over the traffic of a client.
+    pub fn renew(&mut self, id: Key, addr: SocketAddr) -> bool {
+        let ttl = Duration::from_secs(RELAY_SESSION_TTL);
+        self.clients.retain(|_, (_, seen)| seen.elapsed() < ttl);
+
+        match self.clients.get(&id) {
+            Some((current, _)) if *current != addr => return false,
+            None if self.clients.len() >= self.capacity => return false,
+            _ => (),
+        }
+
+        self.clients.insert(id, (addr, Instant::now()));
+        true
+    }
+
+    // where the packets for `id` go, None if we don't relay for it
+    pub fn client(&self, id: &Key) -> Option<SocketAddr> {
+        let ttl = Duration::from_secs(RELAY_SESSION_TTL);
+
+        self.clients
+            .get(id)
+            .filter(|(_, seen)| seen.elapsed() < ttl)
+            .map(|(addr, _)| *addr)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Rpc {
     pub socket: Arc<UdpSocket>,
-    pub pending: Arc<Mutex<HashMap<Key, mpsc::Sender<Option<Response>>>>>,
+    pub pending: Arc<Mutex<HashMap<Key, mpsc::Sender<Reply>>>>,
     pub node: Node,
+    pub external: Arc<Mutex<ExternalAddr>>,
+    // None unless a NAT is simulated
+    pub nat: Option<Arc<Mutex<SimulatedNat>>>,
+    pub sessions: Arc<Mutex<RelaySessions>>,
+    // a light client marks its messages and doesn't serve requests
+    pub client_only: bool,
 }
 
 impl Rpc {
     pub fn new(node: Node) -> Self {
-        let socket = UdpSocket::bind(node.get_addr())
+        let addr = node.addr;
+        Self::with_bind_addr(node, addr)
+    }
+
+    // `node` is what peers know us as, `bind` can differ from its address,
+    // e.g. 0.0.0.0 behind a port mapping
+    pub fn with_bind_addr(node: Node, bind: SocketAddr) -> Self {
+        let socket = UdpSocket::bind(bind)
             .expect("[FAILED] Rpc::new --> Error while binding UdpSocket to specified addr");
 
         Self {
             socket: Arc::new(socket),
             pending: Arc::new(Mutex::new(HashMap::new())),
+            external: Arc::new(Mutex::new(ExternalAddr::new(node.addr, false))),
+            nat: None,
+            sessions: Arc::new(Mutex::new(RelaySessions::new(0))),
+            client_only: false,
             node,
         }
     }
+
+    // our node, with the address peers currently see us at
+    pub fn advertised(&self) -> Node {
+        let external = self
+            .external
+            .lock()
+            .expect("[FAILED] Rpc::advertised --> Failed to acquire lock on External");
+
+        Node {
+            relay: external.relay,
+            ..Node::with_id(external.addr, self.node.id.clone())
+        }
+    }
+
+    // where to send the packets for `dst`: straight to our relay clients,
+    // through their relay for the nodes that have one
+    pub fn route(&self, dst: &Node) -> SocketAddr {
+        let client = self
+            .sessions
+            .lock()
+            .expect("[FAILED] Rpc::route --> Failed to acquire lock on Sessions")
+            .client(&dst.id);
+
+        client.or(dst.relay).unwrap_or(dst.addr)
+    }
+
     pub fn open(rpc: Rpc, sender: mpsc::Sender<ReqWrapper>) {
         thread::spawn(move || {
             let mut buf = [0u8; BUF_SIZE];
 
             loop {
-                let (len, src_addr) = rpc
-                    .socket
-                    .recv_from(&mut buf)
-                    .expect("[FAILED] Rpc::open --> Failed to receive data from peer");
+                let (len, src_addr) = match rpc.socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(e) => {
+                        eprintln!(
+                            "[WARNING] Rpc::open --> Failed to receive data from peer: {}",
+                            e
+                        );
+                        continue;
+                    }
+                };
 
-                let payload =
-                    String::from(str::from_utf8(&buf[..len]).expect(
-                        "[FAILED] Rpc::open --> Unable to parse string from received bytes",
-                    ));
+                // anyone can send us anything, a bad datagram must not stop the loop
+                let payload = match str::from_utf8(&buf[..len]) {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        eprintln!(
+                            "[WARNING] Rpc::open --> Unable to parse string from {}: {}",
+                            src_addr, e
+                        );
+                        continue;
+                    }
+                };
 
-                let mut decoded: RpcMessage = serde_json::from_str(&payload)
-                    .expect("[FAILED] Rpc::open, serde_json --> Unable to decode string payload");
+                let mut decoded: RpcMessage = match serde_json::from_str(payload) {
+                    Ok(decoded) => decoded,
+                    Err(e) => {
+                        eprintln!(
+                            "[WARNING] Rpc::open, serde_json --> Unable to decode payload from {}: {}",
+                            src_addr, e
+                        );
+                        continue;
+                    }
+                };
 
-                decoded.src = src_addr.to_string();
+                // a dual-stack socket sees IPv4 peers as IPv4-mapped IPv6 addresses
+                decoded.src = SocketAddr::new(src_addr.ip().to_canonical(), src_addr.port());
+
+                if let Some(nat) = &rpc.nat {
+                    let nat = nat
+                        .lock()
+                        .expect("[FAILED] Rpc::open --> Failed to acquire lock on Nat");
+                    if !nat.accepts(&decoded.src) {
+                        continue;
+                    }
+                }
+
+                // a message our relay passed on comes from the address it was sent from
+                let our_relay = rpc
+                    .external
+                    .lock()
+                    .expect("[FAILED] Rpc::open --> Failed to acquire lock on External")
+                    .relay;
+                let relayed = match decoded.forwarded_from {
+                    Some(from) if our_relay == Some(decoded.src) => {
+                        decoded.src = from;
+                        true
+                    }
+                    _ => false,
+                };
 
                 if super::VERBOSE {
                     println!(
@@ -96,8 +347,26 @@ impl Rpc {
                     );
                 }
 
-                if decoded.dst != rpc.node.get_addr() {
-                    eprintln!("[WARNING] Rpc::open --> Destination address doesn't match node address, ignoring");
+                // The address a message was sent to says little about us (port mappings, NAT,
+                // unspecified bind addresses), the ID does. A message for another node is dropped,
+                // unless it's one we relay for.
+                if let Some(dst_id) = decoded.dst_id.clone().filter(|id| *id != rpc.node.id) {
+                    let client = rpc
+                        .sessions
+                        .lock()
+                        .expect("[FAILED] Rpc::open --> Failed to acquire lock on Sessions")
+                        .client(&dst_id);
+
+                    match client {
+                        Some(client) => {
+                            decoded.forwarded_from = Some(decoded.src);
+                            decoded.dst = client;
+                            rpc.send_msg(&decoded);
+                        }
+                        None => eprintln!(
+                            "[WARNING] Rpc::open --> Destination ID doesn't match node ID, ignoring"
+                        ),
+                    }
                     continue;
                 }
 
@@ -105,20 +374,33 @@ impl Rpc {
                     Message::Abort => {
                         break;
                     }
+                    // nobody is meant to know of a light client, let alone query it
+                    Message::Request(_) if rpc.client_only => continue,
                     Message::Request(req) => {
                         let wrapped_req = ReqWrapper {
                             token: decoded.token,
                             src: decoded.src,
+                            src_id: decoded.src_id,
+                            src_relay: decoded.src_relay,
+                            relayed,
+                            client_only: decoded.client_only,
                             payload: req,
                         };
 
-                        if let Err(_) = sender.send(wrapped_req) {
+                        if sender.send(wrapped_req).is_err() {
                             eprintln!("[FAILED] Rpc::open, Request --> Receiver is dead, closing channel.");
                             break;
                         }
                     }
+                    // it only had to go through our NAT
+                    Message::Punch => continue,
                     Message::Response(res) => {
-                        rpc.clone().handle_response(decoded.token, res);
+                        rpc.clone().handle_response(
+                            decoded.token,
+                            decoded.src_id,
+                            decoded.observed,
+                            res,
+                        );
                     }
                 }
             }
@@ -128,20 +410,49 @@ impl Rpc {
     pub fn send_msg(&self, msg: &RpcMessage) {
         let encoded = serde_json::to_string(msg)
             .expect("[FAILED] Rpc::send_msg --> Unable to serialize message");
-        self.socket
-            .send_to(&encoded.as_bytes(), &msg.dst)
-            .expect("[FAILED] Rpc::send_msg --> Error while sending message to specified address");
+        let mut dst = msg.dst;
+
+        if let Some(nat) = &self.nat {
+            nat.lock()
+                .expect("[FAILED] Rpc::send_msg --> Failed to acquire lock on Nat")
+                .open(dst);
+        }
+
+        // an IPv6 socket reaches IPv4 peers through IPv4-mapped addresses (dual-stack)
+        let ipv6_socket = self.socket.local_addr().is_ok_and(|addr| addr.is_ipv6());
+        if let (IpAddr::V4(ip), true) = (dst.ip(), ipv6_socket) {
+            dst = SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), dst.port());
+        }
+
+        // e.g. an IPv4 socket can't reach an IPv6 peer, the request times out
+        if let Err(e) = self.socket.send_to(encoded.as_bytes(), dst) {
+            eprintln!(
+                "[WARNING] Rpc::send_msg --> Unable to send message to {}: {}",
+                msg.dst, e
+            );
+        }
     }
 
-    pub fn handle_response(self, token: Key, res: Response) {
+    pub fn handle_response(
+        self,
+        token: Key,
+        src_id: Key,
+        observed: Option<SocketAddr>,
+        res: Response,
+    ) {
         thread::spawn(move || {
             let mut pending = self
                 .pending
                 .lock()
                 .expect("[FAILED] Rpc::handle_response --> Failed to acquire lock on Pending");
 
+            // only answers to our own requests get a vote
+            if let (Some(observed), true) = (observed, pending.contains_key(&token)) {
+                self.observe(src_id.clone(), observed);
+            }
+
             let tmp = match pending.get(&token) {
-                Some(sender) => sender.send(Some(res)),
+                Some(sender) => sender.send(Some((src_id, res))),
                 None => {
                     eprintln!(
                         "[WARNING] Rpc::handle_response --> Unsolicited response received, ignoring..."
@@ -150,13 +461,68 @@ impl Rpc {
                 }
             };
 
-            if let Ok(_) = tmp {
+            if tmp.is_ok() {
                 pending.remove(&token);
             }
         });
     }
 
-    pub fn make_request(&self, req: Request, dst: Node) -> mpsc::Receiver<Option<Response>> {
+    // sends a Message::Punch to `dst`, so that its packets get through our NAT
+    pub fn punch(&self, dst: SocketAddr) {
+        let msg = RpcMessage {
+            token: Key::new(format!(
+                "{}:{}:{:?}",
+                self.node.get_info(),
+                dst,
+                std::time::SystemTime::now()
+            )),
+            src: self.advertised().addr,
+            src_id: self.node.id.clone(),
+            dst,
+            dst_id: None,
+            observed: None,
+            src_relay: None,
+            forwarded_from: None,
+            client_only: self.client_only,
+            msg: Message::Punch,
+        };
+
+        self.send_msg(&msg);
+    }
+
+    // `peer` saw our messages coming from `observed`, see ExternalAddr
+    fn observe(&self, peer: Key, observed: SocketAddr) {
+        // we're in our own routing table, our answers to ourselves don't count
+        if peer == self.node.id {
+            return;
+        }
+
+        let changed = self
+            .external
+            .lock()
+            .expect("[FAILED] Rpc::observe --> Failed to acquire lock on External")
+            .vote(peer, observed);
+
+        if let (Some(addr), true) = (changed, super::VERBOSE) {
+            println!("[+] External address is now {}", addr);
+        }
+    }
+
+    pub fn make_request(&self, req: Request, dst: Node) -> mpsc::Receiver<Reply> {
+        self.send_request(req, self.route(&dst), Some(dst.id))
+    }
+
+    // same as make_request, for a node we only know the address of
+    pub fn make_request_to(&self, req: Request, dst: SocketAddr) -> mpsc::Receiver<Reply> {
+        self.send_request(req, dst, None)
+    }
+
+    fn send_request(
+        &self,
+        req: Request,
+        dst: SocketAddr,
+        dst_id: Option<Key>,
+    ) -> mpsc::Receiver<Reply> {
         let (sender, receiver) = mpsc::channel();
         let mut pending = self
             .pending
@@ -166,15 +532,22 @@ impl Rpc {
         let token = Key::new(format!(
             "{}:{}:{:?}",
             self.node.get_info(),
-            dst.get_info(),
+            dst,
             std::time::SystemTime::now()
         ));
         pending.insert(token.clone(), sender.clone());
 
+        let advertised = self.advertised();
         let msg = RpcMessage {
             token: token.clone(),
-            src: self.node.get_addr(),
-            dst: dst.get_addr(),
+            src: advertised.addr,
+            src_id: self.node.id.clone(),
+            dst,
+            dst_id,
+            observed: None,
+            src_relay: advertised.relay,
+            forwarded_from: None,
+            client_only: self.client_only,
             msg: Message::Request(req),
         };
 
@@ -183,7 +556,7 @@ impl Rpc {
         let rpc = self.clone();
         thread::spawn(move || {
             thread::sleep(std::time::Duration::from_millis(TIMEOUT));
-            if let Ok(_) = sender.send(None) {
+            if sender.send(None).is_ok() {
                 let mut pending = rpc
                     .pending
                     .lock()
@@ -195,3 +568,76 @@ impl Rpc {
         receiver
     }
 }
+
+// Addresses on the wire: the IP bytes (4 for IPv4, 16 for IPv6) followed by the port (2 bytes,
+// big endian), hex encoded. Used with #[serde(with = "compact_addr")].
+pub mod compact_addr {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    pub fn encode(addr: &SocketAddr) -> Vec<u8> {
+        let mut ret = match addr.ip() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        ret.extend_from_slice(&addr.port().to_be_bytes());
+
+        ret
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<SocketAddr> {
+        let (ip, port) = match bytes.len() {
+            6 => {
+                let mut octets = [0u8; 4];
+                octets.copy_from_slice(&bytes[..4]);
+                (IpAddr::V4(Ipv4Addr::from(octets)), &bytes[4..])
+            }
+            18 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&bytes[..16]);
+                (IpAddr::V6(Ipv6Addr::from(octets)), &bytes[16..])
+            }
+            _ => return None,
+        };
+
+        Some(SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]])))
+    }
+
+    pub fn serialize<S: Serializer>(addr: &SocketAddr, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(encode(addr)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SocketAddr, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        let bytes = hex::decode(&encoded).map_err(Error::custom)?;
+
+        decode(&bytes).ok_or_else(|| Error::custom("invalid address length"))
+    }
+
+    // same, for an optional address. Used with #[serde(default, with = "compact_addr::option")]
+    pub mod option {
+        use serde::{Deserialize, Deserializer, Serializer};
+        use std::net::SocketAddr;
+
+        pub fn serialize<S: Serializer>(
+            addr: &Option<SocketAddr>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match addr {
+                Some(addr) => super::serialize(addr, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<SocketAddr>, D::Error> {
+            #[derive(Deserialize)]
+            struct Wrapper(#[serde(with = "super")] SocketAddr);
+
+            let wrapped: Option<Wrapper> = Option::deserialize(deserializer)?;
+            Ok(wrapped.map(|Wrapper(addr)| addr))
+        }
+    }
+}
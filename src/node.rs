@@ -1,28 +1,52 @@
 use super::key::Key;
+use super::network::compact_addr;
 use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
 
 #[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
 pub struct Node {
-    pub ip: String,
-    pub port: u16,
+    // IPv4 or IPv6
+    #[serde(with = "compact_addr")]
+    pub addr: SocketAddr,
     pub id: Key,
+    // a node that can't accept inbound packets is reached through this relay
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "compact_addr::option"
+    )]
+    pub relay: Option<SocketAddr>,
 }
 
 impl Node {
-    pub fn new(ip: String, port: u16) -> Self {
-        let addr = format!("{}:{}", ip, port);
-        let id = Key::new(addr);
+    pub fn new(addr: SocketAddr) -> Self {
+        // "ip:port" for IPv4, "[ip]:port" for IPv6
+        let id = Key::new(addr.to_string());
 
-        Node { ip, port, id }
+        Node {
+            addr,
+            id,
+            relay: None,
+        }
     }
+
+    // a node whose ID isn't derived from its address
+    pub fn with_id(addr: SocketAddr, id: Key) -> Self {
+        Node {
+            addr,
+            id,
+            relay: None,
+        }
+    }
+
     pub fn get_info(&self) -> String {
         let mut parsed_id = hex::encode(self.id.0);
         parsed_id = parsed_id.to_ascii_uppercase();
 
-        format!("{}:{}:{}", self.ip, self.port, parsed_id)
+        format!("{}:{}", self.addr, parsed_id)
     }
 
     pub fn get_addr(&self) -> String {
-        format!("{}:{}", self.ip, self.port)
+        self.addr.to_string()
     }
 }
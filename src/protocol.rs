@@ -1,102 +1,529 @@
+use super::key::{Distance, Key};
+use super::lookup;
 use super::network;
 use super::node::Node;
 use super::routing;
+use super::storage;
 use super::utils;
 
 use crossbeam_channel;
-use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 #[derive(Debug, Clone)]
 pub struct Protocol {
     pub routes: Arc<Mutex<routing::RoutingTable>>,
-    pub store: Arc<Mutex<HashMap<String, String>>>,
+    pub store: Arc<dyn storage::RecordStore>,
+    pub quota: Arc<storage::Quota>,
     pub rpc: Arc<network::Rpc>,
     pub node: Node,
+    pub write_quorum: Option<usize>,
+    pub read_quorum: Option<usize>,
+    pub routes_path: Option<String>,
+    // "host:port" addresses tried in order to join the network
+    pub bootstrap: Vec<String>,
+    pub join_report: JoinReport,
+    // saves the routes once the last handle given to the application is dropped
+    _saver: Option<Arc<RoutesSaver>>,
+}
+
+// see Protocol::worker
+#[derive(Debug)]
+struct RoutesSaver {
+    routes: Arc<Mutex<routing::RoutingTable>>,
+    path: String,
+}
+
+impl Drop for RoutesSaver {
+    fn drop(&mut self) {
+        let routes = match self.routes.lock() {
+            Ok(routes) => routes,
+            Err(_) => return,
+        };
+
+        if let Err(e) = routes.save_contacts(&self.path) {
+            eprintln!(
+                "[WARNING] RoutesSaver::drop --> Unable to save the routing table: {}",
+                e
+            );
+        }
+    }
+}
+
+// knobs used when creating a Protocol, see Protocol::with_config
+#[derive(Debug, Clone)]
+pub struct Config {
+    // backend holding the <key, value> pairs stored on this node
+    pub store: Arc<dyn storage::RecordStore>,
+    // quotas applied to Store requests coming from peers
+    pub limits: storage::StoreLimits,
+    // number of replicas that must accept a put before it returns,
+    // None waits for every replica
+    pub write_quorum: Option<usize>,
+    // number of replicas get waits for before choosing a value,
+    // None stops at the first value found
+    pub read_quorum: Option<usize>,
+    // file the routing table is saved to, and restored from when starting,
+    // None keeps it in memory only
+    pub routes_path: Option<String>,
+    // ID of the node, None derives it from its address
+    pub id: Option<Key>,
+    // address peers reach the node at, when it differs from the one bound
+    // (e.g. bound to 0.0.0.0 and reached through a port mapping)
+    pub advertised: Option<SocketAddr>,
+    // "host:port" addresses of nodes already in the network, tried in order
+    // (after the bootstrap node passed to with_config, if any), see Protocol::join
+    pub bootstrap: Vec<String>,
+    // for tests: only lets in packets from addresses the node sent something to,
+    // like a NAT would (see network::SimulatedNat)
+    pub simulated_nat: bool,
+    // For a node that can't accept inbound packets: it keeps a session with this relay
+    // and peers reach it through it. None is reached directly
+    pub relay: Option<SocketAddr>,
+    // clients this node relays for at most, 0 doesn't relay for anyone
+    pub relay_capacity: usize,
+    // Light client: it can put, get and run lookups, but peers don't add it to their
+    // routing tables (so they don't send it Store requests) and it doesn't serve requests
+    pub client_only: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            store: Arc::new(storage::MemoryStore::new()),
+            limits: storage::StoreLimits::default(),
+            write_quorum: None,
+            read_quorum: None,
+            routes_path: None,
+            id: None,
+            advertised: None,
+            bootstrap: Vec::new(),
+            simulated_nat: false,
+            relay: None,
+            relay_capacity: 0,
+            client_only: false,
+        }
+    }
+}
+
+// outcome of joining the network, see Protocol::join
+#[derive(Debug, Clone, Default)]
+pub struct JoinReport {
+    // the first bootstrap node that answered
+    pub contact: Option<Node>,
+    // bootstrap addresses that couldn't be resolved or didn't answer, with why
+    pub failed: Vec<(String, String)>,
+    // contacts known after the self lookup, ourselves excluded
+    pub known: usize,
+}
+
+impl JoinReport {
+    pub fn joined(&self) -> bool {
+        self.contact.is_some()
+    }
+}
+
+// what the replicas answered to a put, see Protocol::put
+#[derive(Debug, Clone, Default)]
+pub struct PutReport {
+    pub accepted: Vec<Node>,
+    pub rejected: Vec<(Node, storage::Rejection)>,
+    // replicas that didn't answer
+    pub failed: Vec<Node>,
+    // replicas still being contacted when the quorum was reached
+    pub pending: Vec<Node>,
+    // at least write_quorum replicas (or one, without a quorum) accepted the pair
+    pub quorum_reached: bool,
+}
+
+// what the replicas answered to a quorum read, see Protocol::value_lookup_quorum
+#[derive(Debug, Clone, Default)]
+pub struct ReadReport {
+    // every distinct value found, with the replicas that returned it
+    pub values: Vec<(String, Vec<Node>)>,
+    // nodes on the lookup path that answered without the value, closest first
+    pub missing: Vec<routing::NodeAndDistance>,
+    pub quorum_reached: bool,
+}
+
+impl ReadReport {
+    // replicas returned different values
+    pub fn conflict(&self) -> bool {
+        self.values.len() > 1
+    }
+
+    // the value returned by most replicas, ties go to the first one found
+    pub fn value(&self) -> Option<String> {
+        let mut best: Option<&(String, Vec<Node>)> = None;
+        for entry in &self.values {
+            if best.is_none_or(|b| entry.1.len() > b.1.len()) {
+                best = Some(entry);
+            }
+        }
+
+        best.map(|(v, _)| v.clone())
+    }
 }
 
 impl Protocol {
-    pub fn new(ip: String, port: u16, bootstrap: Option<Node>) -> Self {
-        let node = Node::new(ip, port);
+    // `addr` is the address bound, IPv4 or IPv6, an unspecified IPv6 address ([::]) accepts both
+    pub fn new(addr: SocketAddr, bootstrap: Option<Node>) -> Self {
+        Self::with_config(addr, bootstrap, Config::default())
+    }
+
+    pub fn with_config(addr: SocketAddr, bootstrap: Option<Node>, config: Config) -> Self {
+        let advertised = config.advertised.unwrap_or(addr);
+        let node = match config.id {
+            Some(ref id) => Node::with_id(advertised, id.clone()),
+            // "0.0.0.0:PORT" is the same on every host, it can't give a unique ID
+            None if advertised.ip().is_unspecified() => Node::with_id(advertised, Key::random()),
+            None => Node::new(advertised),
+        };
 
-        // channel used for a 2-way communication with the Routing Table module
+        // channel used by the Routing Table module to ask for pings
         let (rt_channel_sender, rt_channel_receiver) = crossbeam_channel::unbounded();
 
-        let routes = routing::RoutingTable::new(
-            node.clone(),
-            bootstrap,
-            rt_channel_sender.clone(),
-            rt_channel_receiver.clone(),
-        );
+        // the bootstrap node is only added once it answers, see join
+        let mut routes = routing::RoutingTable::new(node.clone(), None, rt_channel_sender);
+        // a light client holds no values, it's no candidate for lookups
+        if config.client_only {
+            routes.remove(&node);
+        }
 
         // 1-way channel to communicate with the Network module
         let (rpc_channel_sender, rpc_channel_receiver) = mpsc::channel();
 
-        let rpc = network::Rpc::new(node.clone());
+        let mut rpc = network::Rpc::with_bind_addr(node.clone(), addr);
+        if config.simulated_nat {
+            rpc.nat = Some(Arc::new(Mutex::new(network::SimulatedNat::new())));
+        }
+        rpc.sessions = Arc::new(Mutex::new(network::RelaySessions::new(
+            config.relay_capacity,
+        )));
+        rpc.client_only = config.client_only;
+        // an address set by hand is kept, otherwise peers tell us which one they see
+        rpc.external
+            .lock()
+            .expect("[FAILED] Protocol::with_config --> Failed to acquire lock on External")
+            .fixed = config.advertised.is_some();
         network::Rpc::open(rpc.clone(), rpc_channel_sender);
 
-        let protocol = Self {
-            routes: Arc::new(Mutex::new(routes)),
-            store: Arc::new(Mutex::new(HashMap::new())),
+        let routes = Arc::new(Mutex::new(routes));
+        let mut protocol = Self {
+            _saver: config.routes_path.clone().map(|path| {
+                Arc::new(RoutesSaver {
+                    routes: routes.clone(),
+                    path,
+                })
+            }),
+            routes,
+            store: config.store,
+            quota: Arc::new(storage::Quota::new(node.id.clone(), config.limits)),
             rpc: Arc::new(rpc),
             node: node.clone(),
+            write_quorum: config.write_quorum,
+            read_quorum: config.read_quorum,
+            routes_path: config.routes_path,
+            bootstrap: bootstrap
+                .map(|b| b.get_addr())
+                .into_iter()
+                .chain(config.bootstrap)
+                .collect(),
+            join_report: JoinReport::default(),
         };
 
-        protocol.clone().requests_handler(rpc_channel_receiver);
-        protocol
-            .clone()
-            .rt_forwarder(rt_channel_sender, rt_channel_receiver);
+        protocol.worker().requests_handler(rpc_channel_receiver);
+        protocol.worker().rt_forwarder(rt_channel_receiver);
 
-        // performing node lookup on ourselves
-        protocol.nodes_lookup(&node.id);
+        // the session is opened before joining, so that peers learn we're behind the relay
+        if let Some(relay) = config.relay {
+            if !protocol.open_relay_session(relay) {
+                eprintln!("[WARNING] Protocol::with_config --> The relay refused or didn't answer");
+            }
+
+            let protocol_clone = protocol.worker();
+            std::thread::spawn(move || loop {
+                std::thread::sleep(std::time::Duration::from_secs(super::RELAY_KEEPALIVE));
+                protocol_clone.open_relay_session(relay);
+            });
+        }
+
+        // contacts known before a restart help rejoining even if the bootstrap is down
+        if let Some(path) = &protocol.routes_path {
+            protocol.restore_routes(path);
+
+            let protocol_clone = protocol.worker();
+            std::thread::spawn(move || loop {
+                std::thread::sleep(std::time::Duration::from_secs(super::ROUTES_SAVE_INTERVAL));
+                protocol_clone.save_routes();
+            });
+        }
+
+        protocol.join_report = protocol.join();
+        if !protocol.bootstrap.is_empty() && !protocol.join_report.joined() {
+            eprintln!("[WARNING] Protocol::with_config --> No bootstrap node answered");
+        }
+
+        // bootstrapping again if every contact is gone
+        if !protocol.bootstrap.is_empty() {
+            let protocol_clone = protocol.worker();
+            std::thread::spawn(move || loop {
+                std::thread::sleep(std::time::Duration::from_secs(super::REBOOTSTRAP_INTERVAL));
+
+                let lonely = protocol_clone
+                    .routes
+                    .lock()
+                    .expect("[FAILED] Protocol::with_config --> Failed to acquire mutex on Routes")
+                    .contacts()
+                    .is_empty();
+                if lonely {
+                    protocol_clone.join();
+                }
+            });
+        }
 
         // republishing <key, value> pairs every hour
-        let protocol_clone = protocol.clone();
+        let protocol_clone = protocol.worker();
         std::thread::spawn(move || {
             std::thread::sleep(std::time::Duration::from_secs(60 * 60));
             protocol_clone.republish();
         });
 
+        // dropping expired cached copies every minute
+        let protocol_clone = protocol.worker();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(std::time::Duration::from_secs(60));
+            protocol_clone.quota.expire(&*protocol_clone.store);
+        });
+
         protocol
     }
 
-    fn republish(&self) {
-        let st = self
-            .store
+    // Joins the network: the bootstrap addresses are resolved and pinged in order until one
+    // answers. Every address failing starts a new attempt after a backoff doubling each time,
+    // up to BOOTSTRAP_ATTEMPTS. Then performs a node lookup on ourselves to fill the routing table.
+    pub fn join(&self) -> JoinReport {
+        let mut report = JoinReport::default();
+        let mut backoff = Duration::from_millis(super::BOOTSTRAP_BACKOFF);
+
+        for attempt in 0..super::BOOTSTRAP_ATTEMPTS {
+            if self.bootstrap.is_empty() {
+                break;
+            }
+            if attempt > 0 {
+                std::thread::sleep(backoff);
+                backoff *= 2;
+            }
+
+            for addr in &self.bootstrap {
+                match self.resolve_and_ping(addr) {
+                    Ok(contact) => {
+                        report.failed.retain(|(a, _)| a != addr);
+                        report.contact = Some(contact);
+                        break;
+                    }
+                    Err(e) => {
+                        report.failed.retain(|(a, _)| a != addr);
+                        report.failed.push((addr.clone(), e));
+                    }
+                }
+            }
+
+            if report.joined() {
+                break;
+            }
+        }
+
+        // performing node lookup on ourselves
+        self.nodes_lookup(&self.node.id);
+
+        report.known = self
+            .routes
+            .lock()
+            .expect("[FAILED] Protocol::join --> Failed to acquire mutex on Routes")
+            .contacts()
+            .len();
+        report
+    }
+
+    // Pings every address a "host:port" resolves to, returns the first node answering.
+    // An IPv4 socket can't send to IPv6 addresses, an IPv6 one reaches both.
+    fn resolve_and_ping(&self, addr: &str) -> Result<Node, String> {
+        let v4_only = self
+            .rpc
+            .socket
+            .local_addr()
+            .map(|a| a.is_ipv4())
+            .unwrap_or(false);
+        let resolved: Vec<SocketAddr> = addr
+            .to_socket_addrs()
+            .map_err(|e| e.to_string())?
+            .filter(|a| !(v4_only && a.is_ipv6()))
+            .collect();
+
+        if resolved.is_empty() {
+            return Err("no address of the socket's family".to_owned());
+        }
+
+        for socket_addr in resolved {
+            if let Some(node) = self.ping_addr(socket_addr) {
+                return Ok(node);
+            }
+        }
+
+        Err("no response".to_owned())
+    }
+
+    // Pings a node we only know the address of: its ID is learned from the response,
+    // then it's added to the routing table. None if it didn't answer (or it's ourselves).
+    pub fn ping_addr(&self, addr: SocketAddr) -> Option<Node> {
+        let res = self
+            .rpc
+            .make_request_to(network::Request::Ping, addr)
+            .recv()
+            .expect("[FAILED] Protocol::ping_addr --> Failed to receive response through channel");
+
+        match res {
+            Some((id, network::Response::Ping)) if id != self.node.id => {
+                let node = Node::with_id(addr, id);
+                let mut routes = self
+                    .routes
+                    .lock()
+                    .expect("[FAILED] Protocol::ping_addr --> Failed to acquire mutex on Routes");
+                routes.update(node.clone());
+                routes.mark_reached(&node.id);
+
+                Some(node)
+            }
+            _ => None,
+        }
+    }
+
+    // Opens (or renews) a session with `relay`, which then passes on the messages for us.
+    // Until it accepts one, we advertise no relay.
+    pub fn open_relay_session(&self, relay: SocketAddr) -> bool {
+        let res = self
+            .rpc
+            .make_request_to(network::Request::Relay, relay)
+            .recv()
+            .expect("[FAILED] Protocol::open_relay_session --> Failed to receive response through channel");
+        let accepted = matches!(res, Some((_, network::Response::Relay(true))));
+
+        self.rpc
+            .external
             .lock()
-            .expect("[FAILED] Protocol::republish --> Failed to acquire mutex on Store");
-        for (key, value) in &*st {
-            self.put(key.to_string(), value.to_string());
+            .expect("[FAILED] Protocol::open_relay_session --> Failed to acquire lock on External")
+            .relay = if accepted { Some(relay) } else { None };
+
+        accepted
+    }
+
+    // Our node with the address peers see us at: Config::advertised if set, otherwise
+    // the one most peers observed (see network::ExternalAddr), the bound one until then
+    pub fn advertised(&self) -> Node {
+        self.rpc.advertised()
+    }
+
+    // A handle for the threads running in the background: they may outlive the application's
+    // handles (forever, for the periodic ones), holding the RoutesSaver would keep the routes
+    // from being saved when the application is done.
+    pub(crate) fn worker(&self) -> Self {
+        Self {
+            _saver: None,
+            ..self.clone()
         }
     }
 
-    // forwards upcoming requests (only Pings at the moment) from the Routing table
-    fn rt_forwarder(
-        self,
-        sender: crossbeam_channel::Sender<utils::ChannelPayload>,
-        receiver: crossbeam_channel::Receiver<utils::ChannelPayload>,
-    ) {
+    // Saves the routing table to Config::routes_path (if any). Done periodically
+    // and when the last handle given to the application is dropped.
+    pub fn save_routes(&self) {
+        if let Some(path) = &self.routes_path {
+            let routes = self
+                .routes
+                .lock()
+                .expect("[FAILED] Protocol::save_routes --> Failed to acquire mutex on Routes");
+
+            if let Err(e) = routes.save_contacts(path) {
+                eprintln!(
+                    "[WARNING] Protocol::save_routes --> Unable to save the routing table: {}",
+                    e
+                );
+            }
+        }
+    }
+
+    // Pings the contacts saved in `path`, the ones answering make it into the routing table.
+    fn restore_routes(&self, path: &str) {
+        let contacts = match routing::load_contacts(path) {
+            Ok(contacts) => contacts,
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    eprintln!("[WARNING] Protocol::restore_routes --> Unable to load the routing table: {}", e);
+                }
+                return;
+            }
+        };
+
+        // The most recently seen first, a batch at a time so that a full table doesn't spawn
+        // thousands of threads. Once k contacts answered, the lookup on ourselves in join
+        // finds the rest of the network from them.
+        let mut contacts: Vec<routing::SavedContact> = contacts
+            .into_iter()
+            .filter(|c| c.node.id != self.node.id)
+            .collect();
+        contacts.sort_by_key(|c| std::cmp::Reverse(c.last_seen));
+        let mut alive = Vec::new();
+
+        for batch in contacts.chunks(super::K_PARAM).take(super::RESTORE_BATCHES) {
+            let handles: Vec<_> = batch
+                .iter()
+                .cloned()
+                .map(|c| {
+                    let protocol = self.worker();
+                    std::thread::spawn(move || (protocol.ping(c.node.clone()), c))
+                })
+                .collect();
+
+            alive.extend(
+                handles
+                    .into_iter()
+                    .filter_map(|handle| handle.join().ok())
+                    .filter(|(alive, _)| *alive)
+                    .map(|(_, c)| c),
+            );
+            if alive.len() >= super::K_PARAM {
+                break;
+            }
+        }
+
+        // answers come in any order, the contacts seen last before the restart are kept last
+        self.routes
+            .lock()
+            .expect("[FAILED] Protocol::restore_routes --> Failed to acquire mutex on Routes")
+            .restore(alive);
+    }
+
+    fn republish(&self) {
+        for (key, value) in self.store.iter() {
+            // cached copies are left to expire
+            if !self.quota.is_cached(&key) {
+                self.put(key, value);
+            }
+        }
+    }
+
+    // pings asked by the routing table, the outcome is recorded by Protocol::request
+    fn rt_forwarder(self, receiver: crossbeam_channel::Receiver<utils::ChannelPayload>) {
         std::thread::spawn(move || {
             for req in receiver.iter() {
                 let protocol = self.clone();
-                let sender_clone = sender.clone();
 
                 std::thread::spawn(move || match req {
                     utils::ChannelPayload::Request(payload) => match payload.0 {
                         network::Request::Ping => {
-                            let success = protocol.ping(payload.1);
-                            if success {
-                                if let Err(_) = sender_clone
-                                    .send(utils::ChannelPayload::Response(network::Response::Ping))
-                                {
-                                    eprintln!("[FAILED] Protocol::rt_forwared --> Receiver is dead, closing channel");
-                                }
-                            } else {
-                                if let Err(_) = sender_clone.send(utils::ChannelPayload::NoData) {
-                                    eprintln!("[FAILED] Protocol::rt_forwared --> Receiver is dead, closing channel");
-                                }
-                            }
+                            protocol.ping(payload.1);
                         }
                         _ => {
                             unimplemented!();
@@ -128,36 +555,53 @@ impl Protocol {
     }
 
     fn craft_res(&self, req: network::ReqWrapper) -> (network::Response, network::ReqWrapper) {
+        let relay = self
+            .rpc
+            .external
+            .lock()
+            .expect("[FAILED] Protocol::craft_res --> Failed to acquire lock on External")
+            .relay;
         let mut routes = self
             .routes
             .lock()
             .expect("[FAILED] Protocol::craft_res --> Failed to acquire mutex on Routes");
 
-        // must craft node object because ReqWrapper contains only the src string addr
-        let split = req.src.split(":");
-        let parsed: Vec<&str> = split.collect();
-
-        let src_node = Node::new(
-            parsed[0].to_string(),
-            parsed[1]
-                .parse::<u16>()
-                .expect("[FAILED] Protocol::craft_res --> Failed to parse Node port from address"),
-        );
-        routes.update(src_node);
+        // must craft node object because ReqWrapper contains only the src addr, ID and relay
+        let src_addr = req.src;
+        // Whether the requester is a node we reached out to ourselves at this address (or our
+        // relay): sending us a Ping first isn't enough to be trusted with a punch
+        let known_contact = relay == Some(src_addr)
+            || (routes.is_reached(&req.src_id)
+                && routes
+                    .get(&req.src_id)
+                    .is_some_and(|n| n.addr == src_addr && n.id != self.node.id));
+        let src_node = Node {
+            relay: req.src_relay,
+            ..Node::with_id(src_addr, req.src_id.clone())
+        };
+        // light clients don't take part in routing
+        if !req.client_only {
+            routes.update(src_node);
+        }
         drop(routes);
 
         match req.payload {
             network::Request::Ping => (network::Response::Ping, req),
-            network::Request::Store(ref k, ref v) => {
+            network::Request::Store(ref k, ref v, ttl) => {
                 // ref is used to borrow k and v, which are the contents of req
-
-                let mut store = self
-                    .store
-                    .lock()
-                    .expect("[FAILED] Protocol::craft_res --> Failed to acquire mutex on Store");
-                store.insert(k.to_string(), v.to_string());
-
-                (network::Response::Ping, req)
+                let src_ip = Some(src_addr.ip());
+                let ttl = ttl.map(Duration::from_secs);
+
+                let result =
+                    match self
+                        .quota
+                        .insert(&*self.store, k.to_string(), v.to_string(), src_ip, ttl)
+                    {
+                        Ok(()) => storage::StoreResult::Accepted,
+                        Err(reason) => storage::StoreResult::Rejected(reason),
+                    };
+
+                (network::Response::Store(result), req)
             }
             network::Request::FindNode(ref id) => {
                 let routes = self
@@ -165,359 +609,474 @@ impl Protocol {
                     .lock()
                     .expect("[FAILED] Protocol::craft_res --> Failed to acquire mutex on Routes");
 
-                let result = routes.get_closest_nodes(id, super::K_PARAM);
+                let result = self.advertise_self(routes.get_closest_nodes(id, super::K_PARAM));
 
                 (network::Response::FindNode(result), req)
             }
             network::Request::FindValue(ref k) => {
                 let key = super::key::Key::new(k.to_string());
-                let store = self
-                    .store
-                    .lock()
-                    .expect("[FAILED] Protocol::craft_res --> Failed to acquire mutex on Store");
-
-                let val = store.get(k);
+                if self.quota.is_expired(k) {
+                    self.quota.expire(&*self.store);
+                }
 
-                match val {
-                    Some(v) => (
-                        network::Response::FindValue(routing::FindValueResult::Value(
-                            v.to_string(),
-                        )),
-                        req,
-                    ),
+                match self.store.get(k) {
+                    Some(v) => {
+                        self.quota.touch(k);
+                        (
+                            network::Response::FindValue(routing::FindValueResult::Value(v)),
+                            req,
+                        )
+                    }
                     None => {
                         let routes = self.routes.lock().expect(
                             "[FAILED] Protocol::craft_res --> Failed to acquire mutex on Routes",
                         );
                         (
                             network::Response::FindValue(routing::FindValueResult::Nodes(
-                                routes.get_closest_nodes(&key, super::K_PARAM),
+                                self.advertise_self(routes.get_closest_nodes(&key, super::K_PARAM)),
                             )),
                             req,
                         )
                     }
                 }
             }
+            network::Request::Rendezvous(ref target) => {
+                // only for our contacts, we're not to be used to send packets anywhere
+                let contact = self
+                    .routes
+                    .lock()
+                    .expect("[FAILED] Protocol::craft_res --> Failed to acquire mutex on Routes")
+                    .get(&target.id)
+                    .filter(|n| n.id != self.node.id);
+
+                let punched = match contact {
+                    Some(contact) => matches!(
+                        self.request(contact, network::Request::Punch(src_addr)),
+                        Some(network::Response::Punch(true))
+                    ),
+                    None => false,
+                };
+
+                (network::Response::Rendezvous(punched), req)
+            }
+            network::Request::Punch(addr) => {
+                // Only a node we talk to ourselves (the rendezvous) can ask for a punch:
+                // otherwise anyone could have us send packets to any address
+                if known_contact {
+                    self.rpc.punch(addr);
+                }
+
+                (network::Response::Punch(known_contact), req)
+            }
+            network::Request::Relay => {
+                let accepted = self
+                    .rpc
+                    .sessions
+                    .lock()
+                    .expect("[FAILED] Protocol::craft_res --> Failed to acquire lock on Sessions")
+                    .renew(req.src_id.clone(), src_addr);
+
+                (network::Response::Relay(accepted), req)
+            }
         }
     }
 
+    // our own entry goes out with the address peers currently see us at
+    fn advertise_self(
+        &self,
+        mut entries: Vec<routing::NodeAndDistance>,
+    ) -> Vec<routing::NodeAndDistance> {
+        for entry in entries.iter_mut().filter(|e| e.0.id == self.node.id) {
+            entry.0 = self.advertised();
+        }
+
+        entries
+    }
+
     fn reply(&self, packet_details: (network::Response, network::ReqWrapper)) {
+        let req = &packet_details.1;
+        // a request that came through our relay wasn't sent to us, the requester's NAT
+        // may not let our answer in: it goes through the requester's relay, if any
+        let dst = match (req.relayed, req.src_relay) {
+            (true, Some(relay)) => relay,
+            _ => req.src,
+        };
+        let advertised = self.advertised();
+
         let msg = network::RpcMessage {
-            token: packet_details.1.token,
-            src: self.node.get_addr(),
-            dst: packet_details.1.src,
+            token: req.token.clone(),
+            src: advertised.addr,
+            dst_id: Some(req.src_id.clone()),
+            src_id: self.node.id.clone(),
+            dst,
+            // echoed back so that the requester learns its external address
+            observed: Some(req.src),
+            src_relay: advertised.relay,
+            forwarded_from: None,
+            client_only: self.rpc.client_only,
             msg: network::Message::Response(packet_details.0),
         };
 
         self.rpc.send_msg(&msg);
     }
 
-    pub fn ping(&self, dst: Node) -> bool {
-        let res = utils::make_req_get_res(&self.rpc, network::Request::Ping, dst.clone());
+    // sends any request to `dst` and waits for its response, None if it didn't answer.
+    // The routing table is updated according to the outcome
+    pub fn request(&self, dst: Node, req: network::Request) -> Option<network::Response> {
+        let reply = utils::make_req_get_res(&self.rpc, req, dst.clone());
 
         let mut routes = self
             .routes
             .lock()
-            .expect("[FAILED] Protocol::ping --> Failed to acquire lock on Routes");
+            .expect("[FAILED] Protocol::request --> Failed to acquire mutex on Routes");
+        match reply {
+            Some((id, res)) if id == dst.id => {
+                routes.update(dst);
+                routes.mark_reached(&id);
+                Some(res)
+            }
+            // Someone else answers at that address now (e.g. a hostname resolving elsewhere,
+            // a reused address): `dst` didn't answer, the node that did is learned as itself
+            Some((id, _)) => {
+                eprintln!(
+                    "[WARNING] Protocol::request --> {} answered with another ID, ignoring",
+                    dst.get_addr()
+                );
+                routes.failed(&dst);
+                routes.update(Node { id, ..dst });
+                None
+            }
+            None => {
+                routes.failed(&dst);
+                None
+            }
+        }
+    }
 
-        if let Some(network::Response::Ping) = res {
-            routes.update(dst);
-            true
-        } else {
-            eprintln!(
-                "[WARNING] Protocol::Ping --> No response, removing contact from routing table"
-            );
-            routes.remove(&dst);
-            false
+    // Same as request, but if `dst` doesn't answer and `via` (the node we learned of it from)
+    // gets it to punch a hole towards us, `dst` is tried again. See rendezvous
+    pub fn request_via(
+        &self,
+        dst: Node,
+        req: network::Request,
+        via: Option<Node>,
+    ) -> Option<network::Response> {
+        let retry = req.clone();
+        if let Some(res) = self.request(dst.clone(), req) {
+            return Some(res);
+        }
+
+        match via {
+            Some(via) if via.id != dst.id && self.rendezvous(via.clone(), dst.clone()) => {
+                self.request(dst, retry)
+            }
+            _ => None,
         }
     }
 
-    pub fn store(&self, dst: Node, key: String, val: String) -> bool {
-        let res =
-            utils::make_req_get_res(&self.rpc, network::Request::Store(key, val), dst.clone());
+    // Asks `via` to have `target` (one of its contacts) send a packet towards us: if `target`
+    // is behind a NAT, our packets can then get through it. Returns true if `target` did
+    pub fn rendezvous(&self, via: Node, target: Node) -> bool {
+        matches!(
+            self.request(via, network::Request::Rendezvous(target)),
+            Some(network::Response::Rendezvous(true))
+        )
+    }
 
-        // since we get a ping, update our routing table
-        let mut routes = self
-            .routes
-            .lock()
-            .expect("[FAILED] Protocol::store --> Failed to acquire mutex on Routes");
-        if let Some(network::Response::Ping) = res {
-            routes.update(dst);
+    pub fn ping(&self, dst: Node) -> bool {
+        if let Some(network::Response::Ping) = self.request(dst, network::Request::Ping) {
             true
         } else {
-            routes.remove(&dst);
+            eprintln!("[WARNING] Protocol::Ping --> No response");
             false
         }
     }
 
+    // returns None if the destination didn't answer
+    pub fn store(&self, dst: Node, key: String, val: String) -> Option<storage::StoreResult> {
+        self.store_record(dst, key, val, None)
+    }
+
+    // same as store, but the destination drops the copy once the ttl is over
+    pub fn cache(
+        &self,
+        dst: Node,
+        key: String,
+        val: String,
+        ttl: Duration,
+    ) -> Option<storage::StoreResult> {
+        self.store_record(dst, key, val, Some(ttl.as_secs()))
+    }
+
+    fn store_record(
+        &self,
+        dst: Node,
+        key: String,
+        val: String,
+        ttl: Option<u64>,
+    ) -> Option<storage::StoreResult> {
+        let addr = dst.get_addr();
+
+        match self.request(dst, network::Request::Store(key, val, ttl)) {
+            Some(network::Response::Store(result)) => {
+                if let storage::StoreResult::Rejected(reason) = result {
+                    eprintln!(
+                        "[WARNING] Protocol::store --> Store rejected by {}: {:?}",
+                        addr, reason
+                    );
+                }
+                Some(result)
+            }
+            _ => None,
+        }
+    }
+
     pub fn find_node(
         &self,
         dst: Node,
         id: super::key::Key,
     ) -> Option<Vec<routing::NodeAndDistance>> {
-        let res = utils::make_req_get_res(&self.rpc, network::Request::FindNode(id), dst.clone());
-
-        let mut routes = self
-            .routes
-            .lock()
-            .expect("[FAILED] Protocol::find_node --> Failed to acquire mutex on Routes");
-        if let Some(network::Response::FindNode(entries)) = res {
-            routes.update(dst);
-            Some(entries)
-        } else {
-            routes.remove(&dst);
-            None
+        match self.request(dst, network::Request::FindNode(id)) {
+            Some(network::Response::FindNode(entries)) => Some(entries),
+            _ => None,
         }
     }
 
     pub fn find_value(&self, dst: Node, k: String) -> Option<routing::FindValueResult> {
-        let res = utils::make_req_get_res(&self.rpc, network::Request::FindValue(k), dst.clone());
-
-        let mut routes = self
-            .routes
-            .lock()
-            .expect("[FAILED] Protocol::find_value --> Failed to acquire mutex on Routes");
-
-        if let Some(network::Response::FindValue(val)) = res {
-            routes.update(dst);
-            Some(val)
-        } else {
-            routes.remove(&dst);
-            None
+        match self.request(dst, network::Request::FindValue(k)) {
+            Some(network::Response::FindValue(val)) => Some(val),
+            _ => None,
         }
     }
 
-    pub fn nodes_lookup(&self, id: &super::key::Key) -> Vec<routing::NodeAndDistance> {
-        let mut ret: Vec<routing::NodeAndDistance> = Vec::new();
-
-        // nodes visited
-        let mut queried = HashSet::new();
-        let routes = self
-            .routes
-            .lock()
-            .expect("[FAILED] Protocol::nodes_lookup --> Failed to acquire mutex on Routes");
-
-        // nodes to visit
-        let mut to_query = BinaryHeap::from(routes.get_closest_nodes(id, super::K_PARAM));
-        drop(routes);
+    // runs an iterative lookup towards `target` driven by `strategy` (see lookup.rs),
+    // returns the k closest nodes that responded
+    pub fn lookup<S: lookup::LookupStrategy>(
+        &self,
+        target: &super::key::Key,
+        strategy: &mut S,
+    ) -> Vec<routing::NodeAndDistance> {
+        lookup::run(self, target, strategy)
+    }
 
-        for entry in &to_query {
-            queried.insert(entry.clone());
-        }
+    // same as lookup, `observer` gets every query sent, answer, timeout and the termination
+    pub fn observed_lookup<S: lookup::LookupStrategy>(
+        &self,
+        target: &super::key::Key,
+        strategy: &mut S,
+        observer: &mut dyn FnMut(&lookup::LookupEvent),
+    ) -> Vec<routing::NodeAndDistance> {
+        lookup::run_observed(self, target, strategy, observer)
+    }
 
-        while !to_query.is_empty() {
-            // threads joins
-            let mut joins: Vec<std::thread::JoinHandle<Option<Vec<routing::NodeAndDistance>>>> =
-                Vec::new();
-            // outgoing queries
-            let mut queries: Vec<routing::NodeAndDistance> = Vec::new();
-            let mut results: Vec<Option<Vec<routing::NodeAndDistance>>> = Vec::new();
+    // same as lookup, also returns every event of the lookup
+    pub fn traced_lookup<S: lookup::LookupStrategy>(
+        &self,
+        target: &super::key::Key,
+        strategy: &mut S,
+    ) -> (Vec<routing::NodeAndDistance>, lookup::LookupTrace) {
+        let mut trace = lookup::LookupTrace::new(target.clone());
+        let found = lookup::run_observed(self, target, strategy, &mut |event| trace.record(event));
 
-            for _ in 0..super::ALPHA {
-                match to_query.pop() {
-                    Some(entry) => {
-                        queries.push(entry);
-                    }
-                    None => {
-                        break;
-                    }
-                }
-            }
+        (found, trace)
+    }
 
-            for &routing::NodeAndDistance(ref node, _) in &queries {
-                let n = node.clone();
-                let id_clone = id.clone();
-                let protocol_clone = self.clone();
+    pub fn nodes_lookup(&self, id: &super::key::Key) -> Vec<routing::NodeAndDistance> {
+        self.lookup(id, &mut lookup::FindNodes::new(id.clone()))
+    }
 
-                joins.push(std::thread::spawn(move || {
-                    protocol_clone.find_node(n, id_clone)
-                }));
-            }
+    pub fn value_lookup(&self, k: String) -> (Option<String>, Vec<routing::NodeAndDistance>) {
+        let report = self.value_lookup_quorum(k, 1);
+        let mut nodes = report.missing.clone();
+        nodes.truncate(super::K_PARAM);
 
-            for j in joins {
-                results.push(j.join().expect(
-                    "[FAILED] Protocol::nodes_lookup --> Failed to join thread while visiting nodes",
-                ));
-            }
+        (report.value(), nodes)
+    }
 
-            for (result, query) in results.into_iter().zip(queries) {
-                if let Some(entries) = result {
-                    ret.push(query);
+    // keeps looking up until `quorum` replicas returned the value (or the k closest nodes have been queried),
+    // collecting every distinct value instead of stopping at the first one
+    pub fn value_lookup_quorum(&self, k: String, quorum: usize) -> ReadReport {
+        let key = super::key::Key::new(k.clone());
+        let mut strategy = lookup::FindValue::new(k, quorum);
+        self.lookup(&key, &mut strategy);
 
-                    for entry in entries {
-                        if queried.insert(entry.clone()) {
-                            to_query.push(entry);
-                        }
-                    }
-                }
-            }
-        }
+        strategy.report
+    }
 
-        ret.sort_by(|a, b| a.1.cmp(&b.1));
-        ret.truncate(super::K_PARAM);
+    // nodes closest to the key `k`, found through FindValue requests
+    pub fn search_for_rapprochement(&self, k: String) -> Vec<routing::NodeAndDistance> {
+        let key = super::key::Key::new(k.clone());
 
-        ret
+        // an unreachable quorum doesn't stop at the nodes holding the value
+        self.lookup(&key, &mut lookup::FindValue::new(k, usize::MAX))
     }
 
-    pub fn value_lookup(&self, k: String) -> (Option<String>, Vec<routing::NodeAndDistance>) {
-        // NOTE: k and key are two different things, one is a string used to search for the corresponding value while the other is a key::Key
-
-        let mut ret: Vec<routing::NodeAndDistance> = Vec::new();
-        let key = super::key::Key::new(k.clone());
-        let mut queried = HashSet::new();
+    // stores the pair on the k closest nodes and reports what each replica answered.
+    // With a write quorum it returns as soon as enough replicas accepted the pair,
+    // the remaining stores keep going in the background.
+    pub fn put(&self, k: String, v: String) -> PutReport {
+        let candidates = self.nodes_lookup(&super::key::Key::new(k.clone()));
+        let (sender, receiver) = mpsc::channel();
+        let mut report = PutReport::default();
+        let mut pending: Vec<Node> = Vec::new();
 
-        let routes = self
-            .routes
-            .lock()
-            .expect("[FAILED] Protocol::value_lookup --> Failed to acquire mutex on Routes");
-        let mut to_query = BinaryHeap::from(routes.get_closest_nodes(&key, super::K_PARAM));
-        drop(routes);
+        for routing::NodeAndDistance(node, _) in candidates {
+            let protocol_clone = self.worker();
+            let sender_clone = sender.clone();
+            let k_clone = k.clone();
+            let v_clone = v.clone();
 
-        for entry in &to_query {
-            queried.insert(entry.clone());
+            pending.push(node.clone());
+            std::thread::spawn(move || {
+                let result = protocol_clone.store(node.clone(), k_clone, v_clone);
+                // the receiver is gone once the quorum has been reached
+                let _ = sender_clone.send((node, result));
+            });
         }
+        drop(sender);
 
-        while !to_query.is_empty() {
-            let mut joins: Vec<std::thread::JoinHandle<Option<routing::FindValueResult>>> =
-                Vec::new();
-            let mut queries: Vec<routing::NodeAndDistance> = Vec::new();
-            let mut results: Vec<Option<routing::FindValueResult>> = Vec::new();
+        // a quorum of 0 would report the pair saved with no replica holding it
+        let quorum = self.write_quorum.unwrap_or(1).max(1);
+        for (node, result) in receiver.iter() {
+            pending.retain(|n| n.id != node.id);
 
-            for _ in 0..super::ALPHA {
-                match to_query.pop() {
-                    Some(entry) => {
-                        queries.push(entry);
-                    }
-                    None => {
-                        break;
-                    }
+            match result {
+                Some(storage::StoreResult::Accepted) => report.accepted.push(node),
+                Some(storage::StoreResult::Rejected(reason)) => {
+                    report.rejected.push((node, reason))
                 }
+                None => report.failed.push(node),
             }
 
-            for &routing::NodeAndDistance(ref n, _) in &queries {
-                let k_clone = k.clone();
-                let node = n.clone();
-                let protocol = self.clone();
-
-                joins.push(std::thread::spawn(move || {
-                    protocol.find_value(node, k_clone)
-                }));
-            }
-
-            for j in joins {
-                results.push(j.join().expect("[FAILED] Protocol::value_lookup --> Failed to join thread while searching for value"));
-            }
-
-            for (result, query) in results.into_iter().zip(queries) {
-                if let Some(find_value_result) = result {
-                    match find_value_result {
-                        routing::FindValueResult::Nodes(entries) => {
-                            // we didn't get the value we looked for
-                            ret.push(query);
-                            for entry in entries {
-                                if queried.insert(entry.clone()) {
-                                    to_query.push(entry);
-                                }
-                            }
-                        }
-
-                        routing::FindValueResult::Value(val) => {
-                            ret.sort_by(|a, b| a.1.cmp(&b.1));
-                            ret.truncate(super::K_PARAM);
-
-                            return (Some(val), ret);
-                        }
-                    }
-                }
+            if self.write_quorum.is_some() && report.accepted.len() >= quorum {
+                break;
             }
         }
-        ret.sort_by(|a, b| a.1.cmp(&b.1));
-        ret.truncate(super::K_PARAM);
-        (None, ret)
-    }
 
-    pub fn search_for_rapprochement(&self, k: String) -> Vec<routing::NodeAndDistance> {
-        let mut ret: Vec<routing::NodeAndDistance> = Vec::new();
-        let key = super::key::Key::new(k.clone());
-        let mut queried = HashSet::new();
-
-        let routes = self
-            .routes
-            .lock()
-            .expect("[FAILED] Protocol::search_for_rapprochement --> Failed to acquire mutex on Routes");
-        let mut to_query = BinaryHeap::from(routes.get_closest_nodes(&key, super::K_PARAM));
-        drop(routes);
+        report.quorum_reached = report.accepted.len() >= quorum;
+        report.pending = pending;
+        report
+    }
 
-        for entry in &to_query {
-            queried.insert(entry.clone());
+    pub fn get(&self, k: String) -> Option<String> {
+        let report = self.value_lookup_quorum(k.clone(), self.read_quorum.unwrap_or(1));
+        if report.conflict() {
+            eprintln!(
+                "[WARNING] Protocol::get --> Replicas disagree on the value of {}: {:?}",
+                k, report.values
+            );
         }
 
-        while !to_query.is_empty() {
-            let mut joins: Vec<std::thread::JoinHandle<Option<routing::FindValueResult>>> = Vec::new();
-            let mut queries: Vec<routing::NodeAndDistance> = Vec::new();
-            let mut results: Vec<Option<routing::FindValueResult>> = Vec::new();
+        let val = report.value();
+        if let Some(v) = val.clone() {
+            // Nothing tells which of conflicting values is the fresh one: overwriting a replica
+            // could spread a stale value, only the nodes missing the key are filled.
+            let report = match report.conflict() {
+                true => ReadReport {
+                    values: vec![(
+                        v.clone(),
+                        report.values.iter().flat_map(|(_, n)| n.clone()).collect(),
+                    )],
+                    ..report
+                },
+                false => report,
+            };
+
+            let protocol = self.worker();
+            std::thread::spawn(move || {
+                protocol.read_repair(k, v, &report);
+            });
+        }
 
-            for _ in 0..super::ALPHA {
-                match to_query.pop() {
-                    Some(entry) => queries.push(entry),
-                    None => break
-                }
-            }
+        val
+    }
 
-            for &routing::NodeAndDistance(ref n, _) in &queries {
-                let k_clone = k.clone();
-                let node = n.clone();
-                let protocol = self.clone();
+    // stores `v` on the k closest nodes that answered a lookup without it or with a different value,
+    // plus a cached copy on the closest one past them (see repair_targets).
+    // Returns the nodes that accepted the repair
+    pub fn read_repair(&self, k: String, v: String, report: &ReadReport) -> Vec<Node> {
+        let key = super::key::Key::new(k.clone());
 
-                joins.push(std::thread::spawn(move || protocol.find_value(node, k_clone)));
+        // every node that answered, closest first, and whether it holds `v`
+        let mut path: Vec<routing::NodeAndDistance> = report.missing.clone();
+        for (_, holders) in &report.values {
+            for node in holders {
+                path.push(routing::NodeAndDistance(
+                    node.clone(),
+                    Distance::new(&node.id, &key),
+                ));
             }
+        }
+        path.sort_by_key(|a| a.1);
+
+        let path: Vec<(Node, bool)> = path
+            .into_iter()
+            .map(|routing::NodeAndDistance(node, _)| {
+                let up_to_date = report
+                    .values
+                    .iter()
+                    .any(|(val, holders)| *val == v && holders.iter().any(|n| n.id == node.id));
+                (node, up_to_date)
+            })
+            .collect();
+        let targets = repair_targets(&path);
+
+        let mut joins = Vec::new();
+        for (node, ttl) in targets {
+            let protocol = self.worker();
+            let k_clone = k.clone();
+            let v_clone = v.clone();
 
-            for j in joins {
-                results.push(j.join().expect("[FAILED] Protocol::search_for_rapprochement --> Failed to join thread while searching for value"));
-            }
+            joins.push(std::thread::spawn(move || {
+                let result = match ttl {
+                    Some(ttl) => protocol.cache(node.clone(), k_clone, v_clone, ttl),
+                    None => protocol.store(node.clone(), k_clone, v_clone),
+                };
 
-            for (result, query) in results.into_iter().zip(queries) {       
-                if let Some(routing::FindValueResult::Nodes(entries)) = result {
-                    for entry in entries {
-                        if queried.insert(entry.clone()) {
-                            to_query.push(entry);
-                        }
-                    }
+                match result {
+                    Some(storage::StoreResult::Accepted) => Some(node),
+                    _ => None,
                 }
-                ret.push(query);
-            }
+            }));
         }
-        
-        ret.sort_by(|a, b| a.1.cmp(&b.1));
-        ret.truncate(super::K_PARAM);
-        ret
+
+        joins
+            .into_iter()
+            .filter_map(|j| {
+                j.join().expect(
+                    "[FAILED] Protocol::read_repair --> Failed to join thread while repairing replicas",
+                )
+            })
+            .collect()
     }
+}
 
-    pub fn put(&self, k: String, v: String) {
-        let candidates = self.nodes_lookup(&super::key::Key::new(k.clone()));
+// Where a read repair stores the value, given the nodes on the lookup path closest first and
+// whether they hold it already: a replica on every one of the k closest missing it, a cached copy
+// (None is a replica) on the closest one missing it past them, as the paper asks.
+pub(crate) fn repair_targets(path: &[(Node, bool)]) -> Vec<(Node, Option<Duration>)> {
+    let mut targets = Vec::new();
 
-        for routing::NodeAndDistance(node, _) in candidates {
-            let protocol_clone = self.clone();
-            let k_clone = k.clone();
-            let v_clone = v.clone();
+    for (rank, (node, up_to_date)) in path.iter().enumerate() {
+        if *up_to_date {
+            continue;
+        }
 
-            std::thread::spawn(move || {
-                protocol_clone.store(node, k_clone, v_clone);
-            });
+        if rank < super::K_PARAM {
+            targets.push((node.clone(), None));
+        } else {
+            // only the nodes past the k closest shorten the lifetime
+            targets.push((node.clone(), Some(cache_ttl(rank - super::K_PARAM))));
+            break;
         }
     }
 
-    pub fn get(&self, k: String) -> Option<String> {
-        let (val, mut nodes) = self.value_lookup(k.clone());
-
-        val.map(|v| {
-            if let Some(routing::NodeAndDistance(target, _)) = nodes.pop() {
-                self.store(target, k, v.clone());
-            } else {
-                self.store(self.node.clone(), k, v.clone());
-            }
+    targets
+}
 
-            v
-        })
-    }
+// The paper asks for cached copies to expire faster the further they are from the key:
+// the lifetime is halved for every node between the cache and the k closest ones.
+pub fn cache_ttl(past_closest: usize) -> Duration {
+    // past 16 halvings a day is down to a second
+    Duration::from_secs((super::CACHE_TTL >> past_closest.min(16)).max(1))
 }
@@ -2,13 +2,20 @@ use super::key::{Distance, Key};
 use super::network;
 use super::node::Node;
 use super::utils::ChannelPayload;
+use super::KEY_LEN;
 use super::K_PARAM;
+use super::MAX_FAILURES;
 use super::N_BUCKETS;
+use super::TIMEOUT;
 
 use crossbeam_channel;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Serialize, Deserialize, Eq, Hash, Clone)]
+#[derive(Debug, Serialize, Deserialize, Eq, Clone)]
 pub struct NodeAndDistance(pub Node, pub Distance);
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -17,10 +24,34 @@ pub enum FindValueResult {
     Value(String),
 }
 
+// what the routing table knows about a contact
+#[derive(Debug, Clone, Copy)]
+pub struct ContactInfo {
+    pub last_seen: Instant,
+    // RPCs in a row the contact didn't answer
+    pub failures: usize,
+    // it answered an RPC of ours, not only sent us some, see RoutingTable::mark_reached
+    pub reached: bool,
+}
+
+// a contact as saved to disk, see RoutingTable::save_contacts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedContact {
+    pub node: Node,
+    // seconds since the UNIX epoch
+    pub last_seen: u64,
+}
+
 #[derive(Debug)]
 pub struct KBucket {
     pub nodes: Vec<Node>,
     pub size: usize,
+    // one for each of the nodes, by ID
+    pub contacts: HashMap<Key, ContactInfo>,
+    // nodes heard of while the bucket was full (with when), the most recently seen last
+    pub replacements: Vec<(Node, Instant)>,
+    // the head being pinged because the bucket is full (with when), see RoutingTable::update
+    pub pinging: Option<(Key, Instant)>,
 }
 
 #[derive(Debug)]
@@ -28,7 +59,6 @@ pub struct RoutingTable {
     pub node: Node,
     pub kbuckets: Vec<KBucket>,
     pub sender: crossbeam_channel::Sender<ChannelPayload>,
-    pub receiver: crossbeam_channel::Receiver<ChannelPayload>,
 }
 
 impl PartialEq for NodeAndDistance {
@@ -47,9 +77,19 @@ impl PartialEq for NodeAndDistance {
     }
 }
 
+// Equality only looks at the distance, so hashing must do the same: the derived Hash used to
+// mix in the node, two equal pairs (same distance, different address) could hash apart.
+impl Hash for NodeAndDistance {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.1.hash(state);
+    }
+}
+
+// Pairs are ordered the other way around from their distance, the closest being the greatest
+// (a BinaryHeap pops it first). partial_cmp always returned the same as cmp, it now says so.
 impl PartialOrd for NodeAndDistance {
     fn partial_cmp(&self, other: &NodeAndDistance) -> Option<std::cmp::Ordering> {
-        Some(other.1.cmp(&self.1))
+        Some(self.cmp(other))
     }
 }
 
@@ -60,33 +100,83 @@ impl Ord for NodeAndDistance {
 }
 
 // A k-bucket with index i stores contacts whose ids
-// have a distance between 2^i and 2^i+1 to the own id
+// have a distance between 2^i and 2^(i+1) to the own id (see RoutingTable::bucket_for)
 impl KBucket {
     pub fn new() -> Self {
         Self {
             nodes: Vec::new(),
             size: K_PARAM,
+            contacts: HashMap::new(),
+            replacements: Vec::new(),
+            pinging: None,
+        }
+    }
+
+    fn position(&self, id: &Key) -> Option<usize> {
+        self.nodes.iter().position(|x| x.id == *id)
+    }
+
+    fn add_replacement(&mut self, node: Node) {
+        self.replacements.retain(|(x, _)| x.id != node.id);
+        self.replacements.push((node, Instant::now()));
+
+        if self.replacements.len() > self.size {
+            self.replacements.remove(0);
+        }
+    }
+
+    // drops the i-th node, the most recently seen replacement (if any) takes its place
+    fn evict(&mut self, i: usize) {
+        let node = self.nodes.remove(i);
+        self.contacts.remove(&node.id);
+        self.ping_done(&node.id);
+
+        if let Some((replacement, last_seen)) = self.replacements.pop() {
+            self.contacts.insert(
+                replacement.id.clone(),
+                ContactInfo {
+                    last_seen,
+                    failures: 0,
+                    reached: false,
+                },
+            );
+            self.nodes.push(replacement);
+        }
+    }
+
+    // A ping stays in flight until its node answers or fails, or for TIMEOUT at most:
+    // until then a full bucket doesn't ping its head again for every newcomer.
+    fn ping_pending(&self) -> bool {
+        match self.pinging {
+            Some((_, since)) => since.elapsed() < Duration::from_millis(TIMEOUT),
+            None => false,
+        }
+    }
+
+    fn ping_done(&mut self, id: &Key) {
+        if matches!(self.pinging, Some((ref x, _)) if x == id) {
+            self.pinging = None;
         }
     }
 }
 
+impl Default for KBucket {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RoutingTable {
     pub fn new(
         node: Node,
         bootstrap: Option<Node>,
         sender: crossbeam_channel::Sender<ChannelPayload>,
-        receiver: crossbeam_channel::Receiver<ChannelPayload>,
     ) -> Self {
-        let mut kbuckets: Vec<KBucket> = Vec::new();
-        for _ in 0..N_BUCKETS {
-            kbuckets.push(KBucket::new());
-        }
-
+        // the table starts as a single bucket covering every ID, see split
         let mut ret = Self {
             node: node.clone(),
-            kbuckets,
+            kbuckets: vec![KBucket::new()],
             sender,
-            receiver,
         };
 
         ret.update(node);
@@ -98,32 +188,120 @@ impl RoutingTable {
         ret
     }
 
-    fn get_lookup_bucket_index(&self, key: &Key) -> usize {
-        // https://stackoverflow.com/questions/2656642/easiest-way-to-find-the-correct-kademlia-bucket
+    // Index of the bucket a key belongs to: given a bucket i, we are guaranteed that
+    //  2^i <= distance(node, contact) < 2^(i+1)
+    // so a key with distance d is put in the bucket with index i=⌊log2(d)⌋, the position
+    // of the most significant bit set in the distance. Bucket 0 also holds our own ID (d = 0).
+    pub fn bucket_for(&self, key: &Key) -> usize {
+        let d = Distance::new(&self.node.id, key);
+        for (i, byte) in d.0.iter().enumerate() {
+            if *byte != 0 {
+                return (KEY_LEN - i) * 8 - 1 - byte.leading_zeros() as usize;
+            }
+        }
 
-        // given a bucket j, we are guaranteed that
-        //  2^j <= distance(node, contact) < 2^(j+1)
-        // a node with distance d will be put in the k-bucket with index i=⌊logd⌋
+        0
+    }
 
-        // this is a method I found online but I don't remember the source.
-        // another solution would be computing the log base 10 of the distance
-        // or we could cmp::min the leading zeros of the Distance with the buckets.len() - 1
-        let d = Distance::new(&self.node.id, key);
-        for i in 0..super::KEY_LEN {
-            for j in (0..8).rev() {
-                if (d.0[i] >> (7 - j)) & 0x1 != 0 {
-                    return i * 8 + j;
-                }
+    // lowest and highest key (both included) belonging to the bucket with index i:
+    // they share our ID up to bit i, which is flipped, and bits below it are free
+    pub fn bucket_range(&self, i: usize) -> (Key, Key) {
+        let mut low = self.node.id.clone();
+        let mut high = self.node.id.clone();
+
+        if i > 0 {
+            flip_bit(&mut low.0, i);
+            flip_bit(&mut high.0, i);
+        }
+        for b in 0..i.max(1) {
+            clear_bit(&mut low.0, b);
+            set_bit(&mut high.0, b);
+        }
+
+        (low, high)
+    }
+
+    // a random key belonging to the bucket with index i, used to refresh it
+    pub fn random_key_in_bucket(&self, i: usize) -> Key {
+        let random = Key::random();
+
+        let (mut ret, _) = self.bucket_range(i);
+        for b in 0..i.max(1) {
+            if bit(&random.0, b) {
+                set_bit(&mut ret.0, b);
             }
         }
 
-        super::KEY_LEN * 8 - 1
+        ret
+    }
+
+    // Buckets form a tree that only grows towards our own ID: kbuckets[0] covers every ID
+    // sharing the first N_BUCKETS - kbuckets.len() bits with ours (bucket_for indexes 0..=first),
+    // each kbuckets[j] after it covers the single bucket_for index first + j.
+    // Once the tree is fully split, kbuckets[i] is exactly the bucket_for index i.
+    fn get_bucket_index(&self, key: &Key) -> usize {
+        let first = N_BUCKETS - self.kbuckets.len();
+        self.bucket_for(key).saturating_sub(first)
+    }
+
+    // lowest and highest key (both included) covered by kbuckets[j]
+    fn get_bucket_range(&self, j: usize) -> (Key, Key) {
+        let first = N_BUCKETS - self.kbuckets.len();
+        if j > 0 {
+            return self.bucket_range(first + j);
+        }
+
+        let mut low = self.node.id.clone();
+        let mut high = self.node.id.clone();
+        for b in 0..=first {
+            clear_bit(&mut low.0, b);
+            set_bit(&mut high.0, b);
+        }
+
+        (low, high)
+    }
+
+    // Splits the bucket covering our own ID in two halves: the one further from us
+    // becomes kbuckets[1], the closer one stays kbuckets[0] and can be split again later.
+    fn split(&mut self) {
+        let first = N_BUCKETS - self.kbuckets.len();
+        let near = std::mem::take(&mut self.kbuckets[0]);
+        let mut far = KBucket::new();
+        let is_far = |id: &Key| self.bucket_for(id) == first;
+
+        let (far_nodes, near_nodes): (Vec<Node>, Vec<Node>) =
+            near.nodes.into_iter().partition(|n| is_far(&n.id));
+        let (far_replacements, near_replacements): (Vec<_>, Vec<_>) = near
+            .replacements
+            .into_iter()
+            .partition(|(n, _)| is_far(&n.id));
+
+        far.nodes = far_nodes;
+        far.replacements = far_replacements;
+        let mut kept = KBucket::new();
+        kept.nodes = near_nodes;
+        kept.replacements = near_replacements;
+        match near.pinging {
+            Some((ref id, _)) if is_far(id) => far.pinging = near.pinging,
+            _ => kept.pinging = near.pinging,
+        }
+
+        for (id, info) in near.contacts {
+            match is_far(&id) {
+                true => far.contacts.insert(id, info),
+                false => kept.contacts.insert(id, info),
+            };
+        }
+
+        self.kbuckets[0] = kept;
+        self.kbuckets.insert(1, far);
     }
 
     fn contact_via_rpc(&self, dst: Node) -> bool {
-        if let Err(_) = self
+        if self
             .sender
             .send(ChannelPayload::Request((network::Request::Ping, dst)))
+            .is_err()
         {
             println!(
                 "[FAILED] RoutingTable::contact_via_rpc --> Receiver is dead, closing channel"
@@ -135,112 +313,219 @@ impl RoutingTable {
     }
 
     pub fn update(&mut self, node: Node) {
-        let bucket_idx = self.get_lookup_bucket_index(&node.id);
-
-        // TODO(testing): fill buckets with dummy nodes so we can reach the else statement
-        if self.kbuckets[bucket_idx].nodes.len() < K_PARAM {
-            let node_idx = self.kbuckets[bucket_idx]
-                .nodes
-                .iter()
-                .position(|x| x.id == node.id);
-            match node_idx {
-                Some(i) => {
-                    self.kbuckets[bucket_idx].nodes.remove(i);
-                    self.kbuckets[bucket_idx].nodes.push(node);
-                }
-                None => {
-                    self.kbuckets[bucket_idx].nodes.push(node);
-                }
-            }
+        let mut bucket_idx = self.get_bucket_index(&node.id);
+
+        // a full bucket covering our own ID is split instead of dropping the contact
+        while bucket_idx == 0
+            && self.kbuckets[0].nodes.len() >= K_PARAM
+            && self.kbuckets.len() < N_BUCKETS
+            && !self.kbuckets[0].nodes.iter().any(|x| x.id == node.id)
+        {
+            self.split();
+            bucket_idx = self.get_bucket_index(&node.id);
+        }
+
+        let mut seen = ContactInfo {
+            last_seen: Instant::now(),
+            failures: 0,
+            reached: false,
+        };
+        let bucket = &mut self.kbuckets[bucket_idx];
+        bucket.ping_done(&node.id);
+
+        if let Some(i) = bucket.position(&node.id) {
+            bucket.nodes.remove(i);
+            seen.reached = bucket.contacts.get(&node.id).is_some_and(|c| c.reached);
+            bucket.contacts.insert(node.id.clone(), seen);
+            bucket.nodes.push(node);
+        } else if bucket.nodes.len() < bucket.size {
+            bucket.replacements.retain(|(x, _)| x.id != node.id);
+            bucket.contacts.insert(node.id.clone(), seen);
+            bucket.nodes.push(node);
         } else {
-            let _success = self.contact_via_rpc(self.kbuckets[bucket_idx].nodes[0].clone());
-            let receiver = self.receiver.clone();
-
-            // We must make it blocking (no threads) because we still want to use the mutable reference to the buckets
-            // we don't want to clone and move
-            let res = receiver
-                .recv()
-                .expect("[FAILED] Routing::update --> Failed  to receive data from channel");
-            match res {
-                ChannelPayload::Response(_) => {
-                    let to_re_add = self.kbuckets[bucket_idx].nodes.remove(0);
-                    self.kbuckets[bucket_idx].nodes.push(to_re_add);
-                }
-                ChannelPayload::Request(_) => {
-                    eprintln!("[FAILED] Routing::update --> Unexpectedly got a Request instead of a Response");
-                }
-                ChannelPayload::NoData => {
-                    self.kbuckets[bucket_idx].nodes.remove(0);
-                    self.kbuckets[bucket_idx].nodes.push(node);
-                }
-            };
+            // The newcomer waits as a replacement while the least recently seen contact is pinged:
+            // its answer (or the lack of it) goes through update or failed, without blocking here.
+            bucket.add_replacement(node);
+            if !bucket.ping_pending() {
+                let head = bucket.nodes[0].clone();
+                bucket.pinging = Some((head.id.clone(), Instant::now()));
+                self.contact_via_rpc(head);
+            }
         }
     }
 
+    // A contact didn't answer an RPC. One lost UDP packet must not cost us a good contact:
+    // it's evicted only after MAX_FAILURES RPCs in a row and only if a replacement is available.
+    pub fn failed(&mut self, node: &Node) {
+        let bucket_idx = self.get_bucket_index(&node.id);
+        let bucket = &mut self.kbuckets[bucket_idx];
+
+        bucket.replacements.retain(|(x, _)| x.id != node.id);
+        bucket.ping_done(&node.id);
+
+        let failures = match bucket.contacts.get_mut(&node.id) {
+            Some(info) => {
+                info.failures += 1;
+                info.failures
+            }
+            None => return,
+        };
+
+        if failures >= MAX_FAILURES && !bucket.replacements.is_empty() {
+            if let Some(i) = bucket.position(&node.id) {
+                bucket.evict(i);
+            }
+        }
+    }
+
+    // The contact answered a request of ours: we chose to talk to it, it didn't just
+    // write to us. Anyone can send us a Ping and become a contact, not a reached one.
+    pub fn mark_reached(&mut self, id: &Key) {
+        let bucket_idx = self.get_bucket_index(id);
+
+        if let Some(info) = self.kbuckets[bucket_idx].contacts.get_mut(id) {
+            info.reached = true;
+        }
+    }
+
+    pub fn is_reached(&self, id: &Key) -> bool {
+        let bucket = &self.kbuckets[self.get_bucket_index(id)];
+
+        bucket.contacts.get(id).is_some_and(|c| c.reached)
+    }
+
+    // the contact with the given ID, if we have it
+    pub fn get(&self, id: &Key) -> Option<Node> {
+        let bucket = &self.kbuckets[self.get_bucket_index(id)];
+
+        bucket.position(id).map(|i| bucket.nodes[i].clone())
+    }
+
     pub fn remove(&mut self, node: &Node) {
-        let bucket_idx = self.get_lookup_bucket_index(&node.id);
+        let bucket_idx = self.get_bucket_index(&node.id);
+        let bucket = &mut self.kbuckets[bucket_idx];
 
-        if let Some(i) = self.kbuckets[bucket_idx]
-            .nodes
-            .iter()
-            .position(|x| x.id == node.id)
-        {
-            self.kbuckets[bucket_idx].nodes.remove(i);
+        if let Some(i) = bucket.position(&node.id) {
+            bucket.evict(i);
         } else {
             eprintln!("[WARN] Routing::remove --> Tried to remove non-existing entry");
         }
     }
 
-    pub fn get_closest_nodes(&self, key: &Key, count: usize) -> Vec<NodeAndDistance> {
-        /*
-            Notes:
+    // every contact but ourselves, the most recently seen first
+    pub fn contacts(&self) -> Vec<SavedContact> {
+        let now = SystemTime::now();
+        let mut ret: Vec<SavedContact> = self
+            .kbuckets
+            .iter()
+            .flat_map(|b| b.nodes.iter().map(move |n| (n, b.contacts.get(&n.id))))
+            .filter(|(n, _)| n.id != self.node.id)
+            .map(|(n, info)| {
+                let last_seen = info
+                    .and_then(|info| now.checked_sub(info.last_seen.elapsed()))
+                    .unwrap_or(now);
+
+                SavedContact {
+                    node: n.clone(),
+                    last_seen: last_seen
+                        .duration_since(UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or_default(),
+                }
+            })
+            .collect();
+        ret.sort_by_key(|c| std::cmp::Reverse(c.last_seen));
+
+        ret
+    }
+
+    // Adds contacts loaded by load_contacts (and checked to be alive), the least recently seen
+    // first: every bucket ends up in the order update would have left it in before the restart.
+    pub fn restore(&mut self, mut contacts: Vec<SavedContact>) {
+        contacts.sort_by_key(|c| c.last_seen);
+
+        for contact in contacts {
+            self.update(contact.node);
+        }
+    }
 
-            Kademlia seems not to specify a nice way to search for K closest nodes
+    // writes the contacts to `path` as JSON, see load_contacts
+    pub fn save_contacts(&self, path: &str) -> io::Result<()> {
+        let json = serde_json::to_string(&self.contacts())?;
 
-            # Method 1 (currently in use)
-                1) look at which bucket index the key falls into
-                2) check buckets higher up that index
-                3) check buckets lower down that index
-                (of course stop when you reach the desired count)
+        // written aside first, a crash must not leave a truncated file behind
+        let tmp = format!("{}.tmp", path);
+        std::fs::write(&tmp, json)?;
+        std::fs::rename(tmp, path)
+    }
+
+    // Order in which the buckets hold contacts closer and closer to `key`.
+    // The contacts of a bucket share the bits its lowest and highest keys have in common,
+    // so their distances to the key share them too once XORed with the key: every bucket
+    // covers its own range of distances, starting at that prefix with the other bits cleared.
+    // Ranges don't overlap, sorting the buckets by where they start sorts them by distance.
+    fn get_closest_buckets(&self, key: &Key) -> Vec<usize> {
+        let mut ret: Vec<(Distance, usize)> = (0..self.kbuckets.len())
+            .map(|j| {
+                let (low, high) = self.get_bucket_range(j);
+                let mut d = Distance::new(&low, key);
+                for (i, byte) in d.0.iter_mut().enumerate() {
+                    *byte &= !(low.0[i] ^ high.0[i]);
+                }
+
+                (d, j)
+            })
+            .collect();
+        ret.sort();
 
-            # Method 2 (check issue #2 for a better explaination)
-                1) calculate the distance between two IDs
-                2) visit every kbucket by looking at the XOR result:
-                    - if a bit is set then take its position as an index and visit that kbucket
-                    - bits that are not set are used only after the set ones are used
-        */
+        ret.into_iter().map(|(_, j)| j).collect()
+    }
 
+    // the `count` closest contacts to `key` by XOR distance, sorted
+    pub fn get_closest_nodes(&self, key: &Key, count: usize) -> Vec<NodeAndDistance> {
         let mut ret = Vec::with_capacity(count);
 
         if count == 0 {
             return ret;
         }
 
-        let mut bucket_index = self.get_lookup_bucket_index(key);
-        let mut bucket_index_copy = bucket_index;
-
-        for node in &self.kbuckets[bucket_index].nodes {
-            ret.push(NodeAndDistance(node.clone(), Distance::new(&node.id, key)));
-        }
-
-        while ret.len() < count && bucket_index < self.kbuckets.len() - 1 {
-            bucket_index += 1;
-
+        // since the buckets are visited from the closest distances to the furthest,
+        // once a bucket fills the count no contact left behind can be closer
+        for bucket_index in self.get_closest_buckets(key) {
             for node in &self.kbuckets[bucket_index].nodes {
                 ret.push(NodeAndDistance(node.clone(), Distance::new(&node.id, key)));
             }
-        }
-
-        while ret.len() < count && bucket_index_copy > 0 {
-            bucket_index_copy -= 1;
 
-            for node in &self.kbuckets[bucket_index_copy].nodes {
-                ret.push(NodeAndDistance(node.clone(), Distance::new(&node.id, key)));
+            if ret.len() >= count {
+                break;
             }
         }
 
-        ret.sort_by(|a, b| a.1.cmp(&b.1));
+        ret.sort_by_key(|a| a.1);
         ret.truncate(count);
         ret
     }
 }
+
+// contacts saved by RoutingTable::save_contacts
+pub fn load_contacts(path: &str) -> io::Result<Vec<SavedContact>> {
+    let json = std::fs::read_to_string(path)?;
+    serde_json::from_str(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// bit i of a key or distance, counting from the least significant one
+fn bit(bytes: &[u8; KEY_LEN], i: usize) -> bool {
+    (bytes[KEY_LEN - 1 - i / 8] >> (i % 8)) & 0x1 != 0
+}
+
+fn set_bit(bytes: &mut [u8; KEY_LEN], i: usize) {
+    bytes[KEY_LEN - 1 - i / 8] |= 1 << (i % 8);
+}
+
+fn clear_bit(bytes: &mut [u8; KEY_LEN], i: usize) {
+    bytes[KEY_LEN - 1 - i / 8] &= !(1 << (i % 8));
+}
+
+fn flip_bit(bytes: &mut [u8; KEY_LEN], i: usize) {
+    bytes[KEY_LEN - 1 - i / 8] ^= 1 << (i % 8);
+}
@@ -0,0 +1,422 @@
+use super::key::{Distance, Key};
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Backend used by the Protocol to keep the <key, value> pairs published on this node.
+// Implementations take care of their own synchronization, so the RPC handling
+// never has to hold a lock on the whole store.
+pub trait RecordStore: Debug + Send + Sync {
+    fn get(&self, key: &str) -> Option<String>;
+
+    // inserts or overwrites a pair
+    fn put(&self, key: String, value: String);
+
+    fn remove(&self, key: &str) -> Option<String>;
+
+    // iterates over a snapshot of the stored pairs
+    fn iter(&self) -> Box<dyn Iterator<Item = (String, String)> + '_>;
+
+    // iterates over a snapshot of the stored keys, backends can avoid copying the values
+    fn keys(&self) -> Box<dyn Iterator<Item = String> + '_> {
+        Box::new(self.iter().map(|(k, _)| k))
+    }
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// default in-memory backend, a HashMap behind a Mutex
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    records: Mutex<HashMap<String, String>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl RecordStore for MemoryStore {
+    fn get(&self, key: &str) -> Option<String> {
+        self.records
+            .lock()
+            .expect("[FAILED] MemoryStore::get --> Failed to acquire mutex on Records")
+            .get(key)
+            .cloned()
+    }
+
+    fn put(&self, key: String, value: String) {
+        self.records
+            .lock()
+            .expect("[FAILED] MemoryStore::put --> Failed to acquire mutex on Records")
+            .insert(key, value);
+    }
+
+    fn remove(&self, key: &str) -> Option<String> {
+        self.records
+            .lock()
+            .expect("[FAILED] MemoryStore::remove --> Failed to acquire mutex on Records")
+            .remove(key)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (String, String)> + '_> {
+        let snapshot: Vec<(String, String)> = self
+            .records
+            .lock()
+            .expect("[FAILED] MemoryStore::iter --> Failed to acquire mutex on Records")
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+
+        Box::new(snapshot.into_iter())
+    }
+
+    fn keys(&self) -> Box<dyn Iterator<Item = String> + '_> {
+        let snapshot: Vec<String> = self
+            .records
+            .lock()
+            .expect("[FAILED] MemoryStore::keys --> Failed to acquire mutex on Records")
+            .keys()
+            .cloned()
+            .collect();
+
+        Box::new(snapshot.into_iter())
+    }
+
+    fn len(&self) -> usize {
+        self.records
+            .lock()
+            .expect("[FAILED] MemoryStore::len --> Failed to acquire mutex on Records")
+            .len()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    // drop the records whose key is the furthest from our ID first,
+    // a record further than all the stored ones is rejected instead
+    FurthestFirst,
+    // drop the least recently stored or served record first
+    Lru,
+}
+
+// limits applied to records stored by peers, None means unlimited
+#[derive(Debug, Clone)]
+pub struct StoreLimits {
+    // sum of key and value sizes
+    pub max_bytes: Option<usize>,
+    pub max_records: Option<usize>,
+    pub max_value_size: Option<usize>,
+    pub max_records_per_source: Option<usize>,
+    pub eviction: EvictionPolicy,
+}
+
+impl Default for StoreLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: None,
+            max_records: None,
+            max_value_size: None,
+            max_records_per_source: None,
+            eviction: EvictionPolicy::FurthestFirst,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    ValueTooLarge,
+    SourceQuotaExceeded,
+    StoreFull,
+}
+
+// outcome of a Store request as reported by the contacted node
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreResult {
+    Accepted,
+    Rejected(Rejection),
+}
+
+#[derive(Debug)]
+struct Usage {
+    size: usize,
+    source: Option<IpAddr>,
+    // from our ID to the key, relevant for the FurthestFirst policy
+    distance: Distance,
+    last_access: Instant,
+    // only set on cached copies
+    expires: Option<Instant>,
+}
+
+// the records accounted for, with running totals so that checking a limit doesn't go over them
+#[derive(Debug, Default)]
+struct Ledger {
+    records: HashMap<String, Usage>,
+    bytes: usize,
+    per_source: HashMap<IpAddr, usize>,
+}
+
+impl Ledger {
+    fn add(&mut self, key: String, usage: Usage) {
+        self.bytes += usage.size;
+        if let Some(src) = usage.source {
+            *self.per_source.entry(src).or_default() += 1;
+        }
+
+        if let Some(previous) = self.records.insert(key, usage) {
+            self.forget(&previous);
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Usage> {
+        let usage = self.records.remove(key)?;
+        self.forget(&usage);
+
+        Some(usage)
+    }
+
+    fn forget(&mut self, usage: &Usage) {
+        self.bytes -= usage.size;
+        if let Some(src) = usage.source {
+            if let Some(count) = self.per_source.get_mut(&src) {
+                *count -= 1;
+                if *count == 0 {
+                    self.per_source.remove(&src);
+                }
+            }
+        }
+    }
+}
+
+// Accounting in front of a RecordStore: enforces the StoreLimits on every insertion,
+// evicts records according to the EvictionPolicy when the store is full
+// and keeps track of when cached copies expire.
+#[derive(Debug)]
+pub struct Quota {
+    pub limits: StoreLimits,
+    node_id: Key,
+    usage: Mutex<Ledger>,
+}
+
+impl Quota {
+    pub fn new(node_id: Key, limits: StoreLimits) -> Self {
+        Self {
+            limits,
+            node_id,
+            usage: Mutex::new(Ledger::default()),
+        }
+    }
+
+    pub fn insert(
+        &self,
+        store: &dyn RecordStore,
+        key: String,
+        value: String,
+        source: Option<IpAddr>,
+        ttl: Option<Duration>,
+    ) -> Result<(), Rejection> {
+        if let Some(max) = self.limits.max_value_size {
+            if value.len() > max {
+                return Err(Rejection::ValueTooLarge);
+            }
+        }
+
+        let size = key.len() + value.len();
+        if let Some(max) = self.limits.max_bytes {
+            if size > max {
+                return Err(Rejection::ValueTooLarge);
+            }
+        }
+
+        let mut usage = self
+            .usage
+            .lock()
+            .expect("[FAILED] Quota::insert --> Failed to acquire mutex on Usage");
+        self.sync(store, &mut usage);
+
+        // overwriting a record frees its previous slot
+        let previous = usage.remove(&key);
+        let distance = Distance::new(&self.node_id, &Key::new(key.clone()));
+
+        if let (Some(max), Some(src)) = (self.limits.max_records_per_source, source) {
+            if usage.per_source.get(&src).copied().unwrap_or(0) >= max {
+                if let Some(p) = previous {
+                    usage.add(key, p);
+                }
+                return Err(Rejection::SourceQuotaExceeded);
+            }
+        }
+
+        while self.is_full(&usage, size) {
+            match self.victim(&usage, &distance) {
+                Some(victim) => {
+                    usage.remove(&victim);
+                    store.remove(&victim);
+                }
+                None => {
+                    if let Some(p) = previous {
+                        usage.add(key, p);
+                    }
+                    return Err(Rejection::StoreFull);
+                }
+            }
+        }
+
+        let now = Instant::now();
+        let expires = match (&previous, ttl) {
+            // a cached copy must not make a replica expire
+            (Some(p), Some(_)) if p.expires.is_none() => None,
+            (_, Some(ttl)) => Some(now + ttl),
+            (_, None) => None,
+        };
+
+        usage.add(
+            key.clone(),
+            Usage {
+                size,
+                source,
+                distance,
+                last_access: now,
+                expires,
+            },
+        );
+        store.put(key, value);
+
+        Ok(())
+    }
+
+    // marks a record as used, relevant for the Lru policy
+    pub fn touch(&self, key: &str) {
+        let mut usage = self
+            .usage
+            .lock()
+            .expect("[FAILED] Quota::touch --> Failed to acquire mutex on Usage");
+
+        if let Some(u) = usage.records.get_mut(key) {
+            u.last_access = Instant::now();
+        }
+    }
+
+    // the record is a cached copy that will expire
+    pub fn is_cached(&self, key: &str) -> bool {
+        let usage = self
+            .usage
+            .lock()
+            .expect("[FAILED] Quota::is_cached --> Failed to acquire mutex on Usage");
+
+        usage.records.get(key).is_some_and(|u| u.expires.is_some())
+    }
+
+    pub fn is_expired(&self, key: &str) -> bool {
+        let usage = self
+            .usage
+            .lock()
+            .expect("[FAILED] Quota::is_expired --> Failed to acquire mutex on Usage");
+
+        usage
+            .records
+            .get(key)
+            .and_then(|u| u.expires)
+            .is_some_and(|expires| expires <= Instant::now())
+    }
+
+    // drops the expired cached copies from the store, returns how many were removed
+    pub fn expire(&self, store: &dyn RecordStore) -> usize {
+        let mut usage = self
+            .usage
+            .lock()
+            .expect("[FAILED] Quota::expire --> Failed to acquire mutex on Usage");
+
+        let now = Instant::now();
+        let expired: Vec<String> = usage
+            .records
+            .iter()
+            .filter(|(_, u)| u.expires.is_some_and(|expires| expires <= now))
+            .map(|(k, _)| k.clone())
+            .collect();
+
+        for key in &expired {
+            usage.remove(key);
+            store.remove(key);
+        }
+
+        expired.len()
+    }
+
+    // The limits hold for the whole store: records written without going through the quota
+    // (by the application, or already in a persistent store) are accounted as replicas of
+    // unknown source, the ones removed from the store are forgotten. The store is only gone
+    // over when it doesn't hold as many records as accounted for, then just the values of
+    // the keys we didn't know are read.
+    fn sync(&self, store: &dyn RecordStore, usage: &mut Ledger) {
+        if store.len() == usage.records.len() {
+            return;
+        }
+
+        let present: HashSet<String> = store.keys().collect();
+        let gone: Vec<String> = usage
+            .records
+            .keys()
+            .filter(|key| !present.contains(*key))
+            .cloned()
+            .collect();
+        for key in gone {
+            usage.remove(&key);
+        }
+
+        for key in present {
+            if usage.records.contains_key(&key) {
+                continue;
+            }
+
+            if let Some(value) = store.get(&key) {
+                let u = Usage {
+                    size: key.len() + value.len(),
+                    source: None,
+                    distance: Distance::new(&self.node_id, &Key::new(key.clone())),
+                    last_access: Instant::now(),
+                    expires: None,
+                };
+                usage.add(key, u);
+            }
+        }
+    }
+
+    fn is_full(&self, usage: &Ledger, incoming: usize) -> bool {
+        let records_full = self
+            .limits
+            .max_records
+            .is_some_and(|max| usage.records.len() >= max);
+        let bytes_full = self
+            .limits
+            .max_bytes
+            .is_some_and(|max| usage.bytes + incoming > max);
+
+        records_full || bytes_full
+    }
+
+    fn victim(&self, usage: &Ledger, incoming: &Distance) -> Option<String> {
+        match self.limits.eviction {
+            EvictionPolicy::Lru => usage
+                .records
+                .iter()
+                .min_by_key(|(_, u)| u.last_access)
+                .map(|(k, _)| k.clone()),
+            EvictionPolicy::FurthestFirst => usage
+                .records
+                .iter()
+                .max_by_key(|(_, u)| u.distance)
+                .filter(|(_, u)| u.distance > *incoming)
+                .map(|(k, _)| k.clone()),
+        }
+    }
+}
@@ -3,8 +3,9 @@ use super::protocol::Protocol;
 
 use std::fs::create_dir_all;
 use std::io::Write;
-use std::net::UdpSocket;
+use std::net::{IpAddr, UdpSocket};
 
+use super::lookup::{LookupEvent, LookupTrace};
 use super::network;
 use super::routing::{KBucket, NodeAndDistance};
 
@@ -15,28 +16,31 @@ pub enum ChannelPayload {
     NoData,
 }
 
-pub fn get_local_ip() -> Option<String> {
-    let socket = match UdpSocket::bind("0.0.0.0:0") {
+// address of the interface used to reach the internet, IPv4 first then IPv6
+pub fn get_local_ip() -> Option<IpAddr> {
+    probe_local_ip("0.0.0.0:0", "8.8.8.8:80")
+        .or_else(|| probe_local_ip("[::]:0", "[2001:4860:4860::8888]:80"))
+}
+
+fn probe_local_ip(bind: &str, remote: &str) -> Option<IpAddr> {
+    let socket = match UdpSocket::bind(bind) {
         Ok(s) => s,
         Err(_) => return None,
     };
 
-    match socket.connect("8.8.8.8:80") {
+    match socket.connect(remote) {
         Ok(()) => (),
         Err(_) => return None,
     };
 
     match socket.local_addr() {
-        Ok(addr) => return Some(addr.ip().to_string()),
-        Err(_) => return None,
-    };
+        Ok(addr) => Some(addr.ip()),
+        Err(_) => None,
+    }
 }
 
-pub fn make_req_get_res(
-    rpc: &network::Rpc,
-    req: network::Request,
-    dst: Node,
-) -> Option<network::Response> {
+// the response along with the ID of the node that sent it
+pub fn make_req_get_res(rpc: &network::Rpc, req: network::Request, dst: Node) -> network::Reply {
     rpc.make_request(req, dst)
         .recv()
         .expect("[FAILED] Utils::make_req_get_res --> Failed to receive response through channel")
@@ -50,10 +54,6 @@ pub fn dump_interface_state(interface: &Protocol, path: &str) {
         .routes
         .lock()
         .expect("[FAILED] Utils::dump_interface_state --> Failed to acquire mutex on Routes");
-    let st = interface
-        .store
-        .lock()
-        .expect("[FAILED] Utils::dump_interface_state --> Failed to acquire mutex on Store");
 
     let flattened: Vec<&KBucket> = rt.kbuckets.iter().collect();
 
@@ -62,8 +62,8 @@ pub fn dump_interface_state(interface: &Protocol, path: &str) {
         for n in &kb.nodes {
             let kbucket = serde_json::json!({
                 "nodes": {
-                    "ip": n.ip,
-                    "port": n.port,
+                    "ip": n.addr.ip().to_string(),
+                    "port": n.addr.port(),
                     "id": format!("{:?}", n.id),
                 },
                 "size": kb.size,
@@ -74,21 +74,21 @@ pub fn dump_interface_state(interface: &Protocol, path: &str) {
 
     let mut parsed_store = vec![];
     // parse store
-    for (k, v) in &*st {
+    for (k, v) in interface.store.iter() {
         let obj = serde_json::json!({ k: v });
         parsed_store.push(obj);
     }
 
     let json = serde_json::json!({
         "node": {
-            "ip": interface.node.ip,
-            "port": interface.node.port,
+            "ip": interface.advertised().addr.ip().to_string(),
+            "port": interface.advertised().addr.port(),
             "id": format!("{:?}", interface.node.id),
         },
         "routes": {
             "node": {
-                "ip": rt.node.ip,
-                "port": rt.node.port,
+                "ip": rt.node.addr.ip().to_string(),
+                "port": rt.node.addr.port(),
                 "id": format!("{:?}", interface.node.id),
             },
             "kbuckets": parsed_buckets,
@@ -98,8 +98,8 @@ pub fn dump_interface_state(interface: &Protocol, path: &str) {
             "socket": format!("{:?}", interface.rpc.socket),
             "pending": format!("{:?}", interface.rpc.pending.lock().unwrap()),
             "node": {
-                "ip": interface.rpc.node.ip,
-                "port": interface.rpc.node.port,
+                "ip": interface.rpc.node.addr.ip().to_string(),
+                "port": interface.rpc.node.addr.port(),
                 "id": format!("{:?}", interface.rpc.node.id),
             },
         }
@@ -108,7 +108,7 @@ pub fn dump_interface_state(interface: &Protocol, path: &str) {
     // write to json file
     let mut file = std::fs::File::create(path)
         .expect("[FAILED] Utils::dump_interface_state --> Unable to create dump file");
-    file.write_all(&json.to_string().as_bytes())
+    file.write_all(json.to_string().as_bytes())
         .expect("[FAILED] Utils::dump_interface_state --> Unable to write to dump file");
 
     // write also to a .plantuml file
@@ -119,7 +119,7 @@ pub fn dump_interface_state(interface: &Protocol, path: &str) {
         .expect("[FAILED] Utils::dump_interface_state --> Unable to write to dump file");
 
     diagram
-        .write_all(&json.to_string().as_bytes())
+        .write_all(json.to_string().as_bytes())
         .expect("[FAILED] Utils::dump_interface_state --> Unable to write to dump file");
 
     diagram
@@ -137,8 +137,8 @@ pub fn dump_node_and_distance(
     for e in entries {
         parsed.push(serde_json::json!({
             "node": {
-                "ip": e.0.ip,
-                "port": e.0.port,
+                "ip": e.0.addr.ip().to_string(),
+                "port": e.0.addr.port(),
                 "id": format!("{:?}", e.0.id),
             },
             "distance": format!("{:?}", e.1),
@@ -152,6 +152,79 @@ pub fn dump_node_and_distance(
 
     let mut file = std::fs::File::create(path)
         .expect("[FAILED] Utils::dump_node_and_distance --> Unable to create dump file");
-    file.write_all(&json.to_string().as_bytes())
+    file.write_all(json.to_string().as_bytes())
         .expect("[FAILED] Utils::dump_node_and_distance --> Unable to write to dump file");
 }
+
+pub fn dump_lookup_trace(trace: &LookupTrace, found: &[NodeAndDistance], path: &str) {
+    let node_json = |n: &Node| {
+        serde_json::json!({
+            "ip": n.addr.ip().to_string(),
+            "port": n.addr.port(),
+            "id": format!("{:?}", n.id),
+        })
+    };
+
+    let mut parsed = vec![];
+    for (at, event) in &trace.events {
+        let at_ms = at.as_millis() as u64;
+        let parsed_event = match event {
+            LookupEvent::QuerySent(NodeAndDistance(n, d)) => serde_json::json!({
+                "at_ms": at_ms,
+                "event": "query_sent",
+                "node": node_json(n),
+                "distance": format!("{:?}", d),
+            }),
+            LookupEvent::ResponseReceived {
+                node,
+                elapsed,
+                new_contacts,
+            } => serde_json::json!({
+                "at_ms": at_ms,
+                "event": "response_received",
+                "node": node_json(node),
+                "elapsed_ms": elapsed.as_millis() as u64,
+                "new_contacts": new_contacts,
+            }),
+            LookupEvent::SoftTimeout(n) => serde_json::json!({
+                "at_ms": at_ms,
+                "event": "soft_timeout",
+                "node": node_json(n),
+            }),
+            LookupEvent::Failed { node, elapsed } => serde_json::json!({
+                "at_ms": at_ms,
+                "event": "failed",
+                "node": node_json(node),
+                "elapsed_ms": elapsed.as_millis() as u64,
+            }),
+            LookupEvent::Terminated { reason, queried } => serde_json::json!({
+                "at_ms": at_ms,
+                "event": "terminated",
+                "reason": format!("{:?}", reason),
+                "queried": queried,
+            }),
+        };
+        parsed.push(parsed_event);
+    }
+
+    let parsed_found: Vec<serde_json::Value> = found
+        .iter()
+        .map(|e| {
+            serde_json::json!({
+                "node": node_json(&e.0),
+                "distance": format!("{:?}", e.1),
+            })
+        })
+        .collect();
+
+    let json = serde_json::json!({
+        "target": format!("{:?}", trace.target),
+        "events": parsed,
+        "found": parsed_found,
+    });
+
+    let mut file = std::fs::File::create(path)
+        .expect("[FAILED] Utils::dump_lookup_trace --> Unable to create dump file");
+    file.write_all(json.to_string().as_bytes())
+        .expect("[FAILED] Utils::dump_lookup_trace --> Unable to write to dump file");
+}